@@ -20,23 +20,24 @@
 
 extern crate alloc;
 
-use rustic::Kernel;
+use rustic::{Kernel, print, println, serial_println};
 
-use rustic::arch::{Architecture, Threads, ThreadSpawn};
+use rustic::arch::{Architecture, Scheduling, Threads, ThreadSpawn};
 use rustic::mach::{Keyboard, Screen, Serial, TimerHandlers};
 use rustic::util;
+use rustic::util::logging::{self, info, LevelFilter, ScreenSink};
+use rustic::util::shell::{Console, Shell};
 
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 
 static mut GLOBAL_TICKS: usize = 0;
 
-/*
 fn demo_screen() {
     println!("Hello from the Rustic demo!");
     println!("The Rustic framework is currently providing keyboard handling, so try hitting some keys.");
-    println!("The screen only supports ASCII - no snowmen: ☃☃☃!");
+    println!("The screen only has code page 437 - ╔═╗ and 21°C ± 2° work, but no snowmen: ☃☃☃!");
 }
-*/
 
 fn demo_serial(kernel: &Kernel) {
     kernel.serial_write("Hello from the Rustic demo!\n");
@@ -82,25 +83,27 @@ pub extern "C" fn main(_argc: i32, _: *const *const u8) -> ! {
     kernel.screen_attrib(util::colour::Colour::Black, util::colour::Colour::LightGray);
     kernel.screen_clear();
     kernel.screen_cursor(0, 0);
-    kernel.screen_write("Hello from Rustic");
+    kernel.screen_write("Hello from Rustic\n");
 
     // Demo messages.
-    //demo_screen();
     demo_serial(&kernel);
 
+    // Warnings and errors are worth seeing on the screen too.
+    logging::add_sink(Box::new(ScreenSink), LevelFilter::Warn);
+    info!("example starting");
+
     // Set up our timer handler.
     kernel.register_timer(ticks);
 
-    // Welcome messages.
-    //print!("This is an example where you just want to say... ");
-    //println!("Hello, world!");
-
     // Set LEDs for fun.
     kernel.kb_leds(1);
 
     // Test serial port.
     kernel.serial_write("This is on the serial port, awesome!\n");
 
+    // Switch threads every 50ms even if they don't yield.
+    kernel.set_scheduling(Scheduling::Preemptive(50));
+
     // Test concurrency
     let cloned_kernel = Arc::clone(&locked_kernel);
     kernel.spawn_thread(move || {
@@ -108,7 +111,10 @@ pub extern "C" fn main(_argc: i32, _: *const *const u8) -> ! {
         guard.serial_write("This is ANOTHER thread using the serial port, awesome!\n");
         drop(guard);
 
-        loop { Kernel::reschedule(Arc::clone(&cloned_kernel)) };
+        loop {
+            Kernel::sleep_ms(Arc::clone(&cloned_kernel), 5000);
+            cloned_kernel.lock().unwrap().serial_write("Another five seconds have passed.\n");
+        }
     });
 
     let cloned_kernel = Arc::clone(&locked_kernel);
@@ -120,8 +126,30 @@ pub extern "C" fn main(_argc: i32, _: *const *const u8) -> ! {
         loop { Kernel::reschedule(Arc::clone(&cloned_kernel)) };
     });
 
+    // Echo typed keys to the screen.
+    let cloned_kernel = Arc::clone(&locked_kernel);
+    kernel.spawn_thread(move || {
+        loop {
+            let key = Kernel::read_key();
+            if let Some(c) = key.ch {
+                cloned_kernel.lock().unwrap().screen_write_char(c);
+            }
+        }
+    });
+
+    // Poke around with a shell on the serial port.
+    Shell::new(Console::Serial(0)).spawn(&mut kernel);
+
     drop(kernel);
 
+    // The print macros take the lock themselves, so these wait until it's
+    // been dropped.
+    demo_screen();
+    print!("This is an example where you just want to say... ");
+    println!("Hello, world!");
+    let threads = locked_kernel.lock().unwrap().thread_list().len();
+    serial_println!("{} threads are up.", threads);
+
     loop {
         Kernel::reschedule(Arc::clone(&locked_kernel));
         Kernel::wait_for_event_static();
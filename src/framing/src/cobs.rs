@@ -0,0 +1,149 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Consistent Overhead Byte Stuffing. Encoded data never contains a zero
+// byte, so zero can mark the end of each frame on the wire, and a receiver
+// that joins part way through (or loses bytes) picks up again at the next
+// zero. The overhead is one byte, plus one more per 254 bytes of input.
+
+// Worst case encoded size, not counting the zero delimiter.
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+// Returns the encoded length, or None if the output is too small.
+pub fn encode(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    if output.len() < max_encoded_len(input.len()) {
+        return None;
+    }
+
+    // Each block starts with a code byte: one more than the number of
+    // non-zero bytes that follow it. A block that isn't full stands for
+    // those bytes and then a zero.
+    let mut code_at = 0;
+    let mut out = 1;
+    let mut code = 1u8;
+
+    for &b in input {
+        if b == 0 {
+            output[code_at] = code;
+            code_at = out;
+            out += 1;
+            code = 1;
+            continue;
+        }
+
+        output[out] = b;
+        out += 1;
+        code += 1;
+
+        if code == 0xFF {
+            output[code_at] = code;
+            code_at = out;
+            out += 1;
+            code = 1;
+        }
+    }
+
+    output[code_at] = code;
+    Some(out)
+}
+
+// Takes a frame without its delimiter. Returns the decoded length, or None
+// if the frame is malformed or the output is too small.
+pub fn decode(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut i = 0;
+    let mut out = 0;
+
+    while i < input.len() {
+        let code = input[i] as usize;
+        if code == 0 || i + code > input.len() {
+            return None;
+        }
+        i += 1;
+
+        for &b in &input[i..i + code - 1] {
+            if b == 0 {
+                return None;
+            }
+            *output.get_mut(out)? = b;
+            out += 1;
+        }
+        i += code - 1;
+
+        // The zero a short block stands for, unless it's the last block.
+        if code < 0xFF && i < input.len() {
+            *output.get_mut(out)? = 0;
+            out += 1;
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8], expected: &[u8]) {
+        let mut encoded = [0u8; 1024];
+        let len = encode(input, &mut encoded).unwrap();
+        assert_eq!(expected, &encoded[..len]);
+        assert!(!encoded[..len].contains(&0));
+
+        let mut decoded = [0u8; 1024];
+        let len = decode(&encoded[..len], &mut decoded).unwrap();
+        assert_eq!(input, &decoded[..len]);
+    }
+
+    #[test]
+    fn known_encodings() {
+        round_trip(&[], &[0x01]);
+        round_trip(&[0x00], &[0x01, 0x01]);
+        round_trip(&[0x00, 0x00], &[0x01, 0x01, 0x01]);
+        round_trip(&[0x11, 0x22, 0x00, 0x33], &[0x03, 0x11, 0x22, 0x02, 0x33]);
+        round_trip(&[0x11, 0x00, 0x00, 0x00], &[0x02, 0x11, 0x01, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn long_runs() {
+        // 254 non-zero bytes fill a block exactly.
+        let mut input = [0u8; 300];
+        for (i, b) in input.iter_mut().enumerate() {
+            *b = (i % 255) as u8 + 1;
+        }
+
+        for &len in [253, 254, 255, 300].iter() {
+            let mut encoded = [0u8; 1024];
+            let n = encode(&input[..len], &mut encoded).unwrap();
+            assert!(n <= max_encoded_len(len));
+
+            let mut decoded = [0u8; 1024];
+            let m = decode(&encoded[..n], &mut decoded).unwrap();
+            assert_eq!(&input[..len], &decoded[..m]);
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let mut out = [0u8; 16];
+        assert_eq!(None, decode(&[0x05, 0x11], &mut out));
+        assert_eq!(None, decode(&[0x03, 0x00, 0x11], &mut out));
+        assert_eq!(None, decode(&[0x00], &mut out));
+        assert_eq!(None, encode(&[1, 2, 3], &mut out[..3]));
+        assert_eq!(None, decode(&[0x04, 1, 2, 3], &mut out[..2]));
+    }
+}
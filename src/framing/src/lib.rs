@@ -0,0 +1,515 @@
+#![no_std]
+
+// A link layer for sending messages over a byte stream such as a serial
+// line. Each frame is
+//
+//     kind (1 byte) | sequence (1 byte) | payload | CRC (2 or 4 bytes, LE)
+//
+// COBS encoded and with a zero byte either side, so that anything else
+// written to the line beforehand ends at the first one rather than running
+// into the frame. The CRC covers everything before it. Frames that fail
+// their CRC are dropped, so a message can be sent reliably, in which case
+// the other end acknowledges it and it is sent again until it is. Only one
+// reliable message is in flight at a time.
+//
+// Nothing here does any I/O or keeps time itself, so it all runs on the
+// host. Bytes to send go to a Port; received bytes are fed in one at a
+// time; and the current time is passed in for retransmits.
+
+pub mod cobs;
+pub mod crc;
+pub mod xmodem;
+
+pub const MAX_PAYLOAD: usize = 256;
+
+const HEADER: usize = 2;
+const MAX_CRC: usize = 4;
+const MAX_DECODED: usize = HEADER + MAX_PAYLOAD + MAX_CRC;
+
+// Largest frame on the wire, delimiters included.
+pub const MAX_FRAME: usize = cobs::max_encoded_len(MAX_DECODED) + 2;
+
+const KIND_DATA: u8 = 0;
+const KIND_RELIABLE: u8 = 1;
+const KIND_ACK: u8 = 2;
+
+// Set in the kind of a reliable message that's being sent again. Only those
+// can be repeats, so a peer that has restarted (and numbers from 0 again)
+// isn't mistaken for one repeating its last message.
+const FLAG_RETRY: u8 = 0x80;
+
+// Both ends must use the same one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    Crc16,
+    Crc32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    pub checksum: Checksum,
+    // How long to wait for an acknowledgement before sending again.
+    pub retransmit_ms: u64,
+    // How many times to send again before giving up.
+    pub max_retries: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    TooLong,
+    // A reliable message is still waiting to be acknowledged.
+    Busy,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    Message(&'a [u8]),
+    // The reliable message in flight was acknowledged.
+    Acked,
+    // The reliable message in flight was never acknowledged.
+    Failed,
+}
+
+// Where frames are written.
+pub trait Port {
+    fn write(&mut self, bytes: &[u8]);
+}
+
+// Collects bytes into frames, and checks them.
+pub struct Decoder {
+    checksum: Checksum,
+    raw: [u8; MAX_FRAME],
+    len: usize,
+    // The frame in progress is too long, so is dropped at its delimiter.
+    overflow: bool,
+    decoded: [u8; MAX_DECODED],
+}
+
+struct Frame {
+    kind: u8,
+    seq: u8,
+    // Where the payload sits in Decoder::decoded.
+    len: usize,
+}
+
+struct Pending {
+    seq: u8,
+    payload: [u8; MAX_PAYLOAD],
+    len: usize,
+    sent_at: u64,
+    retries: usize,
+}
+
+pub struct Link {
+    config: Config,
+    decoder: Decoder,
+    tx_seq: u8,
+    // Reliable messages are numbered separately, so that unreliable ones in
+    // between can't bring the number back round to the last one's.
+    tx_reliable_seq: u8,
+    // Sequence number of the last reliable message received, so that
+    // retransmits of it (when our ack was lost) aren't passed on twice.
+    // Messages sent for the first time are passed on whatever their number.
+    rx_seq: Option<u8>,
+    pending: Option<Pending>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config{checksum: Checksum::Crc16, retransmit_ms: 200, max_retries: 5}
+    }
+}
+
+impl Checksum {
+    fn len(self) -> usize {
+        match self {
+            Checksum::Crc16 => 2,
+            Checksum::Crc32 => 4,
+        }
+    }
+
+    // Writes the checksum of `data` into `out`, which is len() bytes.
+    fn write(self, data: &[u8], out: &mut [u8]) {
+        match self {
+            Checksum::Crc16 => out.copy_from_slice(&crc::crc16(data).to_le_bytes()),
+            Checksum::Crc32 => out.copy_from_slice(&crc::crc32(data).to_le_bytes()),
+        }
+    }
+}
+
+// Writes a whole frame to the port.
+fn write_frame<P: Port>(port: &mut P, checksum: Checksum, kind: u8, seq: u8, payload: &[u8]) -> Result<(), Error> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(Error::TooLong);
+    }
+
+    let mut frame = [0u8; MAX_DECODED];
+    let len = HEADER + payload.len();
+    frame[0] = kind;
+    frame[1] = seq;
+    frame[HEADER..len].copy_from_slice(payload);
+
+    let crc_len = checksum.len();
+    let (data, crc) = frame.split_at_mut(len);
+    checksum.write(data, &mut crc[..crc_len]);
+
+    // The decoder ignores the empty frame ending at the first delimiter.
+    let mut encoded = [0u8; MAX_FRAME];
+    let n = cobs::encode(&frame[..len + crc_len], &mut encoded[1..]).unwrap();
+    encoded[n + 1] = 0;
+    port.write(&encoded[..n + 2]);
+    Ok(())
+}
+
+impl Decoder {
+    pub const fn new(checksum: Checksum) -> Decoder {
+        Decoder{checksum, raw: [0; MAX_FRAME], len: 0, overflow: false, decoded: [0; MAX_DECODED]}
+    }
+
+    fn push(&mut self, byte: u8) -> Option<Frame> {
+        if byte != 0 {
+            if self.len < self.raw.len() {
+                self.raw[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        let len = self.len;
+        let overflow = self.overflow;
+        self.len = 0;
+        self.overflow = false;
+        if overflow || len == 0 {
+            return None;
+        }
+
+        let n = cobs::decode(&self.raw[..len], &mut self.decoded)?;
+        let crc_len = self.checksum.len();
+        if n < HEADER + crc_len {
+            return None;
+        }
+
+        let mut crc = [0u8; MAX_CRC];
+        self.checksum.write(&self.decoded[..n - crc_len], &mut crc[..crc_len]);
+        if crc[..crc_len] != self.decoded[n - crc_len..n] {
+            return None;
+        }
+
+        Some(Frame{kind: self.decoded[0], seq: self.decoded[1], len: n - crc_len - HEADER})
+    }
+
+    fn payload(&self, frame: &Frame) -> &[u8] {
+        &self.decoded[HEADER..HEADER + frame.len]
+    }
+}
+
+impl Link {
+    pub const fn new(config: Config) -> Link {
+        Link{config, decoder: Decoder::new(config.checksum), tx_seq: 0, tx_reliable_seq: 0, rx_seq: None, pending: None}
+    }
+
+    // Whether a reliable message is waiting to be acknowledged.
+    pub fn busy(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn send<P: Port>(&mut self, port: &mut P, payload: &[u8], reliable: bool, now_ms: u64) -> Result<(), Error> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(Error::TooLong);
+        }
+
+        if reliable && self.pending.is_some() {
+            return Err(Error::Busy);
+        }
+
+        let counter = if reliable { &mut self.tx_reliable_seq } else { &mut self.tx_seq };
+        let seq = *counter;
+        *counter = counter.wrapping_add(1);
+
+        let kind = if reliable { KIND_RELIABLE } else { KIND_DATA };
+        write_frame(port, self.config.checksum, kind, seq, payload)?;
+
+        if reliable {
+            let mut pending = Pending{seq, payload: [0; MAX_PAYLOAD], len: payload.len(), sent_at: now_ms, retries: 0};
+            pending.payload[..payload.len()].copy_from_slice(payload);
+            self.pending = Some(pending);
+        }
+
+        Ok(())
+    }
+
+    // Feeds in one received byte. Reliable messages are acknowledged as
+    // they arrive.
+    pub fn receive<P: Port>(&mut self, port: &mut P, byte: u8) -> Option<Event<'_>> {
+        let frame = self.decoder.push(byte)?;
+
+        let retry = frame.kind & FLAG_RETRY != 0;
+        match frame.kind & !FLAG_RETRY {
+            KIND_ACK => match self.pending {
+                Some(ref pending) if pending.seq == frame.seq => {
+                    self.pending = None;
+                    Some(Event::Acked)
+                },
+                _ => None
+            },
+            KIND_RELIABLE => {
+                // Acked even if it's a repeat, as the last ack was lost.
+                let _ = write_frame(port, self.config.checksum, KIND_ACK, frame.seq, &[]);
+                if retry && self.rx_seq == Some(frame.seq) {
+                    return None;
+                }
+
+                self.rx_seq = Some(frame.seq);
+                Some(Event::Message(self.decoder.payload(&frame)))
+            },
+            KIND_DATA => Some(Event::Message(self.decoder.payload(&frame))),
+            _ => None
+        }
+    }
+
+    // Call this regularly while busy() to send the message in flight again
+    // if it hasn't been acknowledged in time. Gives Failed once it runs out
+    // of retries, after which the link can send again.
+    pub fn poll<P: Port>(&mut self, port: &mut P, now_ms: u64) -> Option<Event<'static>> {
+        let pending = self.pending.as_mut()?;
+        if now_ms.saturating_sub(pending.sent_at) < self.config.retransmit_ms {
+            return None;
+        }
+
+        if pending.retries == self.config.max_retries {
+            self.pending = None;
+            return Some(Event::Failed);
+        }
+
+        pending.retries += 1;
+        pending.sent_at = now_ms;
+        let _ = write_frame(port, self.config.checksum, KIND_RELIABLE | FLAG_RETRY, pending.seq, &pending.payload[..pending.len]);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records everything written, as one long byte stream.
+    struct Wire {
+        bytes: [u8; 4096],
+        len: usize,
+    }
+
+    impl Wire {
+        fn new() -> Wire {
+            Wire{bytes: [0; 4096], len: 0}
+        }
+
+        fn take(&mut self) -> ([u8; 4096], usize) {
+            let taken = (self.bytes, self.len);
+            self.len = 0;
+            taken
+        }
+    }
+
+    impl Port for Wire {
+        fn write(&mut self, bytes: &[u8]) {
+            self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+    }
+
+    // Feeds everything `from` wrote into `to`, returning the messages (as
+    // the last one's bytes) and acks seen.
+    fn deliver(from: &mut Wire, to: &mut Link, replies: &mut Wire) -> (usize, [u8; MAX_PAYLOAD], usize, usize) {
+        let (bytes, len) = from.take();
+        let mut messages = 0;
+        let mut last = [0u8; MAX_PAYLOAD];
+        let mut last_len = 0;
+        let mut acks = 0;
+        for &b in &bytes[..len] {
+            match to.receive(replies, b) {
+                Some(Event::Message(m)) => {
+                    messages += 1;
+                    last[..m.len()].copy_from_slice(m);
+                    last_len = m.len();
+                },
+                Some(Event::Acked) => acks += 1,
+                _ => {}
+            }
+        }
+        (messages, last, last_len, acks)
+    }
+
+    #[test]
+    fn unreliable_messages() {
+        for &checksum in [Checksum::Crc16, Checksum::Crc32].iter() {
+            let config = Config{checksum, ..Config::default()};
+            let (mut a, mut b) = (Link::new(config), Link::new(config));
+            let (mut a_out, mut b_out) = (Wire::new(), Wire::new());
+
+            a.send(&mut a_out, b"hello\0world", false, 0).unwrap();
+            a.send(&mut a_out, b"", false, 0).unwrap();
+            assert!(!a.busy());
+
+            let (messages, _, last_len, _) = deliver(&mut a_out, &mut b, &mut b_out);
+            assert_eq!(2, messages);
+            assert_eq!(0, last_len);
+
+            // Nothing to acknowledge.
+            assert_eq!(0, b_out.len);
+        }
+    }
+
+    #[test]
+    fn corrupted_frames_are_dropped() {
+        let mut a = Link::new(Config::default());
+        let mut b = Link::new(Config::default());
+        let (mut a_out, mut b_out) = (Wire::new(), Wire::new());
+
+        a.send(&mut a_out, b"first", false, 0).unwrap();
+        a_out.bytes[3] ^= 0x40;
+        a.send(&mut a_out, b"second", false, 0).unwrap();
+
+        let (messages, last, last_len, _) = deliver(&mut a_out, &mut b, &mut b_out);
+        assert_eq!(1, messages);
+        assert_eq!(b"second", &last[..last_len]);
+    }
+
+    #[test]
+    fn junk_before_a_frame() {
+        let mut a = Link::new(Config::default());
+        let mut b = Link::new(Config::default());
+        let (mut a_out, mut b_out) = (Wire::new(), Wire::new());
+
+        a_out.write(b"debug output with no delimiter\n");
+        a.send(&mut a_out, b"message", false, 0).unwrap();
+
+        let (messages, last, last_len, _) = deliver(&mut a_out, &mut b, &mut b_out);
+        assert_eq!(1, messages);
+        assert_eq!(b"message", &last[..last_len]);
+    }
+
+    #[test]
+    fn reliable_message_is_acked() {
+        let mut a = Link::new(Config::default());
+        let mut b = Link::new(Config::default());
+        let (mut a_out, mut b_out) = (Wire::new(), Wire::new());
+
+        a.send(&mut a_out, b"ping", true, 0).unwrap();
+        assert!(a.busy());
+        assert_eq!(Err(Error::Busy), a.send(&mut a_out, b"again", true, 0));
+
+        let (messages, last, last_len, _) = deliver(&mut a_out, &mut b, &mut b_out);
+        assert_eq!(1, messages);
+        assert_eq!(b"ping", &last[..last_len]);
+
+        let (_, _, _, acks) = deliver(&mut b_out, &mut a, &mut a_out);
+        assert_eq!(1, acks);
+        assert!(!a.busy());
+    }
+
+    #[test]
+    fn unreliable_messages_dont_reuse_reliable_numbers() {
+        let mut a = Link::new(Config::default());
+        let mut b = Link::new(Config::default());
+        let (mut a_out, mut b_out) = (Wire::new(), Wire::new());
+
+        a.send(&mut a_out, b"first", true, 0).unwrap();
+        deliver(&mut a_out, &mut b, &mut b_out);
+        deliver(&mut b_out, &mut a, &mut a_out);
+        assert!(!a.busy());
+
+        for _ in 0..255 {
+            a.send(&mut a_out, b"", false, 0).unwrap();
+            deliver(&mut a_out, &mut b, &mut b_out);
+        }
+
+        a.send(&mut a_out, b"second", true, 0).unwrap();
+        let (messages, last, last_len, _) = deliver(&mut a_out, &mut b, &mut b_out);
+        assert_eq!(1, messages);
+        assert_eq!(b"second", &last[..last_len]);
+    }
+
+    #[test]
+    fn peer_restarts() {
+        let mut a = Link::new(Config::default());
+        let mut b = Link::new(Config::default());
+        let (mut a_out, mut b_out) = (Wire::new(), Wire::new());
+
+        a.send(&mut a_out, b"before", true, 0).unwrap();
+        deliver(&mut a_out, &mut b, &mut b_out);
+        deliver(&mut b_out, &mut a, &mut a_out);
+
+        // The new link numbers its first message 0, as the old one did.
+        let mut a = Link::new(Config::default());
+        a.send(&mut a_out, b"after", true, 0).unwrap();
+        let (messages, last, last_len, _) = deliver(&mut a_out, &mut b, &mut b_out);
+        assert_eq!(1, messages);
+        assert_eq!(b"after", &last[..last_len]);
+
+        let (_, _, _, acks) = deliver(&mut b_out, &mut a, &mut a_out);
+        assert_eq!(1, acks);
+    }
+
+    #[test]
+    fn lost_frames_are_retransmitted() {
+        let config = Config{retransmit_ms: 100, max_retries: 2, ..Config::default()};
+        let mut a = Link::new(config);
+        let mut b = Link::new(config);
+        let (mut a_out, mut b_out) = (Wire::new(), Wire::new());
+
+        // The first copy never arrives.
+        a.send(&mut a_out, b"data", true, 0).unwrap();
+        a_out.take();
+        assert_eq!(None, a.poll(&mut a_out, 99));
+        assert_eq!(0, a_out.len);
+        assert_eq!(None, a.poll(&mut a_out, 100));
+
+        // The second does, but its ack is lost, so it arrives again.
+        let (messages, _, _, _) = deliver(&mut a_out, &mut b, &mut b_out);
+        assert_eq!(1, messages);
+        b_out.take();
+
+        assert_eq!(None, a.poll(&mut a_out, 200));
+        let (messages, _, _, _) = deliver(&mut a_out, &mut b, &mut b_out);
+        assert_eq!(0, messages);
+
+        let (_, _, _, acks) = deliver(&mut b_out, &mut a, &mut a_out);
+        assert_eq!(1, acks);
+        assert!(!a.busy());
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let config = Config{retransmit_ms: 10, max_retries: 2, ..Config::default()};
+        let mut a = Link::new(config);
+        let mut out = Wire::new();
+
+        a.send(&mut out, b"anyone?", true, 0).unwrap();
+        assert_eq!(None, a.poll(&mut out, 10));
+        assert_eq!(None, a.poll(&mut out, 20));
+        assert_eq!(Some(Event::Failed), a.poll(&mut out, 30));
+        assert!(!a.busy());
+        a.send(&mut out, b"next", true, 30).unwrap();
+    }
+
+    #[test]
+    fn oversized() {
+        let mut a = Link::new(Config::default());
+        let mut out = Wire::new();
+        assert_eq!(Err(Error::TooLong), a.send(&mut out, &[0; MAX_PAYLOAD + 1], false, 0));
+        a.send(&mut out, &[0xAA; MAX_PAYLOAD], false, 0).unwrap();
+
+        // Junk that's too long for a frame is thrown away at the delimiter.
+        let mut b = Link::new(Config::default());
+        let mut replies = Wire::new();
+        for _ in 0..MAX_FRAME * 2 {
+            assert_eq!(None, b.receive(&mut replies, 0x55));
+        }
+        assert_eq!(None, b.receive(&mut replies, 0));
+        let (messages, last, last_len, _) = deliver(&mut out, &mut b, &mut replies);
+        assert_eq!(1, messages);
+        assert_eq!(&[0xAA; MAX_PAYLOAD][..], &last[..last_len]);
+    }
+}
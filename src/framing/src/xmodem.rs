@@ -0,0 +1,565 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// XMODEM-CRC and YMODEM file transfers, as spoken by sx/rx and sb/rb (or
+// `sz --xmodem` and `sz --ymodem`) from lrzsz and most terminal programs.
+//
+// XMODEM sends one file as numbered 128 or 1024 byte blocks, each with a
+// CRC-16, and has each block acknowledged before the next. The receiver
+// can't tell padding from data, so the last block arrives padded out with
+// SUB (0x1A) bytes. YMODEM sends a batch of files this way, each with a
+// block zero that gives its name and size first, and an empty block zero to
+// end the batch.
+
+use crate::Port;
+use crate::crc::crc16;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1A;
+// Sent by the receiver to ask for blocks with CRCs, not checksums.
+const CRC_START: u8 = b'C';
+
+// How long to wait for the start of a block, and for each byte within it.
+const BLOCK_TIMEOUT_MS: u64 = 10_000;
+const BYTE_TIMEOUT_MS: u64 = 1_000;
+
+// The receiver asks for the transfer to start every few seconds, for about
+// a minute, to give someone time to start the sender.
+const START_INTERVAL_MS: u64 = 3_000;
+const START_TRIES: usize = 20;
+
+const MAX_ERRORS: usize = 10;
+
+const BLOCK_SIZE: usize = 128;
+const BLOCK_1K: usize = 1024;
+
+// A serial line, or anything else that can stand in for one.
+pub trait Line: Port {
+    // Waits up to the given time for a byte.
+    fn read(&mut self, timeout_ms: u64) -> Option<u8>;
+}
+
+// Where received files go.
+pub trait Sink {
+    // Called at the start of each file. XMODEM files have no name or size.
+    // Return false to refuse the file, which cancels the transfer.
+    fn file(&mut self, name: &str, size: Option<usize>) -> bool;
+
+    // Return false if the data can't be taken, which cancels the transfer.
+    fn write(&mut self, data: &[u8]) -> bool;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    // The other end never started, or went quiet.
+    Timeout,
+    // The other end cancelled the transfer.
+    Cancelled,
+    // Too many bad blocks in a row, or blocks out of order.
+    TooManyErrors,
+    // The sink refused the data.
+    Refused,
+    // A YMODEM file name too long for block zero.
+    NameTooLong,
+}
+
+enum Packet {
+    // Block number and length, with the data in the buffer.
+    Data(u8, usize),
+    Eot,
+    Cancel,
+}
+
+enum Bad {
+    Timeout,
+    Corrupt,
+}
+
+// Receives a single file. Returns how many bytes were received, padding
+// included.
+pub fn xmodem_receive<L: Line, S: Sink>(line: &mut L, sink: &mut S) -> Result<usize, Error> {
+    if !sink.file("", None) {
+        cancel(line);
+        return Err(Error::Refused);
+    }
+
+    receive_blocks(line, sink, None, false)
+}
+
+// Receives files until the sender ends the batch. Returns how many files
+// were received.
+pub fn ymodem_receive<L: Line, S: Sink>(line: &mut L, sink: &mut S) -> Result<usize, Error> {
+    let mut buf = [0u8; BLOCK_1K];
+    let mut files = 0;
+
+    loop {
+        let len = receive_header(line, &mut buf)?;
+        let (name, size) = parse_header(&buf[..len]);
+
+        line.write(&[ACK]);
+        if name.is_empty() {
+            return Ok(files);
+        }
+
+        if !sink.file(name, size) {
+            cancel(line);
+            return Err(Error::Refused);
+        }
+
+        receive_blocks(line, sink, size, true)?;
+        files += 1;
+    }
+}
+
+pub fn xmodem_send<L: Line>(line: &mut L, data: &[u8]) -> Result<(), Error> {
+    wait_for_start(line)?;
+    send_blocks(line, data, BLOCK_SIZE)?;
+    send_eot(line)
+}
+
+// Sends each (name, data) pair as a file, then ends the batch.
+pub fn ymodem_send<L: Line>(line: &mut L, files: &[(&str, &[u8])]) -> Result<(), Error> {
+    let mut header = [0u8; BLOCK_SIZE];
+
+    for &(name, data) in files {
+        // Name, NUL, then the size in decimal.
+        let mut size = [0u8; 20];
+        let size = decimal(data.len(), &mut size);
+        let end = name.len() + 1 + size.len();
+        if name.is_empty() || end > BLOCK_SIZE {
+            cancel(line);
+            return Err(Error::NameTooLong);
+        }
+
+        header.iter_mut().for_each(|b| *b = 0);
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[name.len() + 1..end].copy_from_slice(size);
+
+        wait_for_start(line)?;
+        send_block(line, 0, &header, BLOCK_SIZE)?;
+
+        // The receiver asks again before the data starts.
+        wait_for_start(line)?;
+        send_blocks(line, data, BLOCK_1K)?;
+        send_eot(line)?;
+    }
+
+    header.iter_mut().for_each(|b| *b = 0);
+    wait_for_start(line)?;
+    send_block(line, 0, &header, BLOCK_SIZE)
+}
+
+fn receive_header<L: Line>(line: &mut L, buf: &mut [u8; BLOCK_1K]) -> Result<usize, Error> {
+    for _ in 0..START_TRIES {
+        line.write(&[CRC_START]);
+
+        match read_packet(line, buf, START_INTERVAL_MS) {
+            Ok(Packet::Data(0, len)) => return Ok(len),
+            Ok(Packet::Cancel) => return Err(Error::Cancelled),
+            // Probably the end of a transfer we missed the start of.
+            Ok(Packet::Eot) => line.write(&[ACK]),
+            Ok(Packet::Data(..)) | Err(Bad::Corrupt) => purge(line),
+            Err(Bad::Timeout) => {}
+        }
+    }
+
+    cancel(line);
+    Err(Error::Timeout)
+}
+
+// Block zero holds the name, a NUL, and then the size in decimal followed
+// by other things we don't care about (modification time and so on).
+fn parse_header(block: &[u8]) -> (&str, Option<usize>) {
+    let end = block.iter().position(|&b| b == 0).unwrap_or(block.len());
+    let name = core::str::from_utf8(&block[..end]).unwrap_or("unnamed");
+
+    let rest = block.get(end + 1..).unwrap_or(&[]);
+    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    let size = core::str::from_utf8(&rest[..digits]).ok().and_then(|s| s.parse().ok());
+
+    (name, size)
+}
+
+// Receives the data blocks of a file, from block one to the EOT. Anything
+// past `size` is padding, and isn't passed on.
+fn receive_blocks<L: Line, S: Sink>(line: &mut L, sink: &mut S, mut size: Option<usize>, ymodem: bool) -> Result<usize, Error> {
+    let mut buf = [0u8; BLOCK_1K];
+    let mut expected: u8 = 1;
+    let mut started = false;
+    let mut errors = 0;
+    let mut total = 0;
+    let mut eot_seen = false;
+
+    line.write(&[CRC_START]);
+
+    loop {
+        let timeout = if started { BLOCK_TIMEOUT_MS } else { START_INTERVAL_MS };
+        let bad = match read_packet(line, &mut buf, timeout) {
+            Ok(Packet::Data(block, len)) if block == expected => {
+                started = true;
+                errors = 0;
+
+                let len = match size {
+                    Some(ref mut remaining) => {
+                        let len = len.min(*remaining);
+                        *remaining -= len;
+                        len
+                    },
+                    None => len
+                };
+
+                if !sink.write(&buf[..len]) {
+                    cancel(line);
+                    return Err(Error::Refused);
+                }
+
+                total += len;
+                expected = expected.wrapping_add(1);
+                line.write(&[ACK]);
+                continue;
+            },
+            Ok(Packet::Data(block, _)) if block == expected.wrapping_sub(1) => {
+                // A repeat, as our ack was lost.
+                line.write(&[ACK]);
+                continue;
+            },
+            Ok(Packet::Data(..)) => {
+                cancel(line);
+                return Err(Error::TooManyErrors);
+            },
+            Ok(Packet::Eot) => {
+                // YMODEM senders expect the first EOT to be refused, as a
+                // check that it really is the end.
+                if ymodem && !eot_seen {
+                    eot_seen = true;
+                    line.write(&[NAK]);
+                    continue;
+                }
+
+                line.write(&[ACK]);
+                return Ok(total);
+            },
+            Ok(Packet::Cancel) => return Err(Error::Cancelled),
+            Err(bad) => bad
+        };
+
+        errors += 1;
+        if errors == if started { MAX_ERRORS } else { START_TRIES } {
+            cancel(line);
+            return Err(if started { Error::TooManyErrors } else { Error::Timeout });
+        }
+
+        if let Bad::Corrupt = bad {
+            purge(line);
+        }
+        line.write(&[if started { NAK } else { CRC_START }]);
+    }
+}
+
+fn read_packet<L: Line>(line: &mut L, buf: &mut [u8; BLOCK_1K], timeout_ms: u64) -> Result<Packet, Bad> {
+    let len = match line.read(timeout_ms) {
+        Some(SOH) => BLOCK_SIZE,
+        Some(STX) => BLOCK_1K,
+        Some(EOT) => return Ok(Packet::Eot),
+        // A lone CAN could be line noise.
+        Some(CAN) if line.read(BYTE_TIMEOUT_MS) == Some(CAN) => return Ok(Packet::Cancel),
+        Some(_) => return Err(Bad::Corrupt),
+        None => return Err(Bad::Timeout)
+    };
+
+    let block = read_byte(line)?;
+    let inverse = read_byte(line)?;
+    for b in buf[..len].iter_mut() {
+        *b = read_byte(line)?;
+    }
+    let crc = u16::from_be_bytes([read_byte(line)?, read_byte(line)?]);
+
+    if block != !inverse || crc != crc16(&buf[..len]) {
+        return Err(Bad::Corrupt);
+    }
+
+    Ok(Packet::Data(block, len))
+}
+
+fn read_byte<L: Line>(line: &mut L) -> Result<u8, Bad> {
+    line.read(BYTE_TIMEOUT_MS).ok_or(Bad::Corrupt)
+}
+
+// Throws away the rest of a bad block.
+fn purge<L: Line>(line: &mut L) {
+    while line.read(BYTE_TIMEOUT_MS).is_some() {}
+}
+
+// Throws away whatever has already arrived, without waiting for more.
+fn discard<L: Line>(line: &mut L) {
+    while line.read(0).is_some() {}
+}
+
+fn cancel<L: Line>(line: &mut L) {
+    line.write(&[CAN, CAN, CAN]);
+}
+
+// Only CRC mode is supported, so a receiver asking for checksums (with NAK)
+// is ignored until it gives up and asks for CRCs.
+fn wait_for_start<L: Line>(line: &mut L) -> Result<(), Error> {
+    for _ in 0..START_TRIES {
+        match line.read(START_INTERVAL_MS) {
+            Some(CRC_START) => return Ok(()),
+            Some(CAN) if line.read(BYTE_TIMEOUT_MS) == Some(CAN) => return Err(Error::Cancelled),
+            _ => {}
+        }
+    }
+
+    cancel(line);
+    Err(Error::Timeout)
+}
+
+fn send_blocks<L: Line>(line: &mut L, data: &[u8], size: usize) -> Result<(), Error> {
+    for (i, chunk) in data.chunks(size).enumerate() {
+        // Small leftovers go in a small block, rather than padding a 1K one.
+        let size = if chunk.len() <= BLOCK_SIZE { BLOCK_SIZE } else { size };
+        send_block(line, (i + 1) as u8, chunk, size)?;
+    }
+
+    Ok(())
+}
+
+fn send_block<L: Line>(line: &mut L, block: u8, data: &[u8], size: usize) -> Result<(), Error> {
+    let mut packet = [SUB; 3 + BLOCK_1K + 2];
+    packet[0] = if size == BLOCK_1K { STX } else { SOH };
+    packet[1] = block;
+    packet[2] = !block;
+    packet[3..3 + data.len()].copy_from_slice(data);
+    let crc = crc16(&packet[3..3 + size]);
+    packet[3 + size..5 + size].copy_from_slice(&crc.to_be_bytes());
+
+    for _ in 0..MAX_ERRORS {
+        // Anything still waiting (such as a start request sent before the
+        // receiver saw the last block) isn't a reply to this block.
+        discard(line);
+        line.write(&packet[..5 + size]);
+
+        // Only a NAK or silence means the block should be sent again. Until
+        // it has a good first block, the receiver asks again for the start
+        // rather than sending NAKs.
+        loop {
+            match line.read(BLOCK_TIMEOUT_MS) {
+                Some(ACK) => return Ok(()),
+                Some(CAN) if line.read(BYTE_TIMEOUT_MS) == Some(CAN) => return Err(Error::Cancelled),
+                Some(NAK) | None => break,
+                Some(CRC_START) if block <= 1 => break,
+                Some(_) => {}
+            }
+        }
+    }
+
+    cancel(line);
+    Err(Error::TooManyErrors)
+}
+
+fn send_eot<L: Line>(line: &mut L) -> Result<(), Error> {
+    for _ in 0..MAX_ERRORS {
+        line.write(&[EOT]);
+
+        if line.read(BLOCK_TIMEOUT_MS) == Some(ACK) {
+            return Ok(());
+        }
+    }
+
+    Err(Error::TooManyErrors)
+}
+
+fn decimal(mut n: usize, buf: &mut [u8; 20]) -> &[u8] {
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            return &buf[i..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::string::String;
+    use std::thread;
+    use std::time::Duration;
+    use std::vec::Vec;
+
+    // One end of a pretend serial cable. If `corrupt` is set, that byte of
+    // what's written (counting from the start) is flipped on its way.
+    struct End {
+        tx: Sender<u8>,
+        rx: Receiver<u8>,
+        written: usize,
+        corrupt: Option<usize>,
+    }
+
+    impl Port for End {
+        fn write(&mut self, bytes: &[u8]) {
+            for &b in bytes {
+                let b = if self.corrupt == Some(self.written) { b ^ 0x08 } else { b };
+                self.written += 1;
+                let _ = self.tx.send(b);
+            }
+        }
+    }
+
+    impl Line for End {
+        fn read(&mut self, timeout_ms: u64) -> Option<u8> {
+            self.rx.recv_timeout(Duration::from_millis(timeout_ms)).ok()
+        }
+    }
+
+    fn cable(corrupt: Option<usize>) -> (End, End) {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        (End{tx: a_tx, rx: a_rx, written: 0, corrupt},
+         End{tx: b_tx, rx: b_rx, written: 0, corrupt: None})
+    }
+
+    #[derive(Default)]
+    struct Files {
+        files: Vec<(String, Option<usize>, Vec<u8>)>,
+    }
+
+    impl Sink for Files {
+        fn file(&mut self, name: &str, size: Option<usize>) -> bool {
+            self.files.push((String::from(name), size, Vec::new()));
+            true
+        }
+
+        fn write(&mut self, data: &[u8]) -> bool {
+            self.files.last_mut().unwrap().2.extend_from_slice(data);
+            true
+        }
+    }
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn xmodem_round_trip() {
+        let data = test_data(300);
+        let (mut sender, mut receiver) = cable(None);
+
+        let sent = data.clone();
+        let send = thread::spawn(move || xmodem_send(&mut sender, &sent));
+
+        let mut files = Files::default();
+        assert_eq!(Ok(384), xmodem_receive(&mut receiver, &mut files));
+        assert_eq!(Ok(()), send.join().unwrap());
+
+        // Padded out to a whole number of blocks.
+        let received = &files.files[0].2;
+        assert_eq!(&data[..], &received[..300]);
+        assert!(received[300..].iter().all(|&b| b == SUB));
+    }
+
+    #[test]
+    fn ymodem_round_trip() {
+        let small = test_data(5);
+        let large = test_data(3000);
+        let (mut sender, mut receiver) = cable(None);
+
+        let (a, b) = (small.clone(), large.clone());
+        let send = thread::spawn(move || {
+            ymodem_send(&mut sender, &[("small.bin", &a), ("empty", &[]), ("large.bin", &b)])
+        });
+
+        let mut files = Files::default();
+        assert_eq!(Ok(3), ymodem_receive(&mut receiver, &mut files));
+        assert_eq!(Ok(()), send.join().unwrap());
+
+        assert_eq!(3, files.files.len());
+        assert_eq!(("small.bin", Some(5), &small), (files.files[0].0.as_str(), files.files[0].1, &files.files[0].2));
+        assert_eq!(("empty", Some(0)), (files.files[1].0.as_str(), files.files[1].1));
+        assert!(files.files[1].2.is_empty());
+        assert_eq!(("large.bin", Some(3000), &large), (files.files[2].0.as_str(), files.files[2].1, &files.files[2].2));
+    }
+
+    #[test]
+    fn corrupted_block_is_resent() {
+        let data = test_data(200);
+
+        // Somewhere in the middle of the first block's data.
+        let (mut sender, mut receiver) = cable(Some(40));
+
+        let sent = data.clone();
+        let send = thread::spawn(move || xmodem_send(&mut sender, &sent));
+
+        let mut files = Files::default();
+        assert_eq!(Ok(256), xmodem_receive(&mut receiver, &mut files));
+        assert_eq!(Ok(()), send.join().unwrap());
+        assert_eq!(&data[..], &files.files[0].2[..200]);
+    }
+
+    #[test]
+    fn stale_start_requests_are_ignored() {
+        let data = test_data(300);
+
+        // Somewhere in the second block, were the first sent three times
+        // (once for each request). The acks for the repeats would then be
+        // taken for acks of the blocks after it.
+        let (mut sender, mut receiver) = cable(Some(3 * 133 + 40));
+
+        // A receiver that was started early and is still asking when the
+        // sender picks up its first request.
+        receiver.write(&[CRC_START, CRC_START]);
+
+        let sent = data.clone();
+        let send = thread::spawn(move || xmodem_send(&mut sender, &sent));
+
+        let mut files = Files::default();
+        assert_eq!(Ok(384), xmodem_receive(&mut receiver, &mut files));
+        assert_eq!(Ok(()), send.join().unwrap());
+        assert_eq!(&data[..], &files.files[0].2[..300]);
+    }
+
+    #[test]
+    fn refused_file_cancels() {
+        struct Refuse;
+        impl Sink for Refuse {
+            fn file(&mut self, _: &str, _: Option<usize>) -> bool { false }
+            fn write(&mut self, _: &[u8]) -> bool { false }
+        }
+
+        let (mut sender, mut receiver) = cable(None);
+        let send = thread::spawn(move || ymodem_send(&mut sender, &[("nope", b"data")]));
+
+        assert_eq!(Err(Error::Refused), ymodem_receive(&mut receiver, &mut Refuse));
+        assert_eq!(Err(Error::Cancelled), send.join().unwrap());
+    }
+
+    #[test]
+    fn header_parsing() {
+        assert_eq!(("foo.txt", Some(1234)), parse_header(b"foo.txt\x001234 13774407544 100644\0\0"));
+        assert_eq!(("bar", None), parse_header(b"bar\0\0\0"));
+        assert_eq!(("", None), parse_header(&[0; 128]));
+    }
+}
@@ -0,0 +1,99 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use core::fmt::Write;
+
+use super::idt::TrapFrame;
+
+static EXCEPTION_NAMES: [&'static str; 32] = [
+    "Divide Error",
+    "Debug",
+    "Non-Maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "BOUND Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+static PAGE_FAULT: u32 = 14;
+
+// Page fault error code bits.
+static PF_PRESENT: u32 = 1 << 0;
+static PF_WRITE: u32 = 1 << 1;
+static PF_USER: u32 = 1 << 2;
+static PF_FETCH: u32 = 1 << 4;
+
+// Default handler for CPU exceptions: dump everything we know about the
+// fault to the debug port and stop. Returning would just re-run the faulting
+// instruction forever.
+pub fn exception_trap(frame: &mut TrapFrame) {
+    let name = exception_name(frame.vector);
+
+    // Errors writing to the debug port are not interesting here.
+    let _ = dump_frame(&mut crate::Debug{}, name, frame);
+
+    panic!("{} at EIP {:#010x}", name, frame.eip);
+}
+
+pub fn exception_name(vector: u32) -> &'static str {
+    match EXCEPTION_NAMES.get(vector as usize) {
+        Some(name) => name,
+        None => "Unknown"
+    }
+}
+
+fn dump_frame<W: Write>(w: &mut W, name: &str, frame: &TrapFrame) -> core::fmt::Result {
+    write!(w, "\n*** Exception {} ({}), error code {:#x}\n", frame.vector, name, frame.error_code)?;
+
+    if frame.vector == PAGE_FAULT {
+        let code = frame.error_code;
+        write!(w, "Faulting address {:#010x}: {} {} in {} mode\n",
+               frame.cr2,
+               if (code & PF_FETCH) != 0 { "instruction fetch" } else if (code & PF_WRITE) != 0 { "write" } else { "read" },
+               if (code & PF_PRESENT) != 0 { "violated page protection" } else { "of a non-present page" },
+               if (code & PF_USER) != 0 { "user" } else { "kernel" })?;
+    }
+
+    write!(w, "EAX={:08x} EBX={:08x} ECX={:08x} EDX={:08x}\n", frame.eax, frame.ebx, frame.ecx, frame.edx)?;
+    write!(w, "ESI={:08x} EDI={:08x} EBP={:08x} ESP={:08x}\n", frame.esi, frame.edi, frame.ebp, frame.esp)?;
+    write!(w, "EIP={:08x} EFLAGS={:08x} CR2={:08x}\n", frame.eip, frame.eflags, frame.cr2)?;
+    write!(w, "CS={:04x} DS={:04x} ES={:04x} FS={:04x} GS={:04x}\n", frame.cs, frame.ds, frame.es, frame.fs, frame.gs)
+}
@@ -0,0 +1,559 @@
+/*
+ * Copyright (c) 2013 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// A GDB remote serial protocol stub. It takes over the breakpoint and debug
+// traps, and talks to GDB by polling the serial port directly - everything
+// here runs in a trap handler with interrupts off, and possibly with the
+// kernel locked, so it can't allocate or lock anything.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::Kernel;
+use crate::arch::{Architecture, Debugger};
+use crate::mach::SerialPorts;
+
+use super::idt::TrapFrame;
+use super::paging;
+
+static NO_PORT: usize = usize::MAX;
+
+static DEBUG_TRAP: usize = 1;
+static BREAKPOINT_TRAP: usize = 3;
+
+static INT3: u8 = 0xCC;
+static EFLAGS_TF: u32 = 1 << 8;
+
+// Signals reported to GDB when we stop.
+static SIGINT: u8 = 2;
+static SIGTRAP: u8 = 5;
+
+// Largest packet we'll take from GDB, which is also the largest we send.
+const PACKET_SIZE: usize = 512;
+
+const MAX_BREAKPOINTS: usize = 32;
+
+// Registers in the order GDB expects them for i386.
+static NUM_REGISTERS: usize = 16;
+
+static PORT: AtomicUsize = AtomicUsize::new(NO_PORT);
+
+// GDB asked to stop the running system.
+static BREAK_PENDING: AtomicBool = AtomicBool::new(false);
+
+// GDB resumed us and is waiting to hear that we stopped again.
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+// Only touched from trap handlers with interrupts off, so never shared.
+static mut BREAKPOINTS: [Option<Breakpoint>; MAX_BREAKPOINTS] = [None; MAX_BREAKPOINTS];
+static mut PACKET: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+static mut REPLY: Reply = Reply::new();
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    addr: usize,
+    // What the int3 replaced.
+    saved: u8,
+}
+
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+// What to do once a packet has been dealt with.
+enum Action {
+    // Send the reply and wait for the next packet.
+    Stay,
+    // Carry on without replying. GDB isn't waiting for one (it wants the
+    // stop reply for c and s, and has gone after k).
+    Resume,
+    // Send the reply, then carry on.
+    ReplyAndResume,
+}
+
+impl Reply {
+    const fn new() -> Reply {
+        Reply{buf: [0; PACKET_SIZE], len: 0}
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // Anything that doesn't fit is cut off, and all of our replies fit.
+    fn push(&mut self, b: u8) {
+        if self.len < PACKET_SIZE {
+            self.buf[self.len] = b;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for b in s.bytes() {
+            self.push(b);
+        }
+    }
+
+    fn push_hex(&mut self, b: u8) {
+        self.push(hex_digit(b >> 4));
+        self.push(hex_digit(b & 0xF));
+    }
+
+    // Registers go over the wire in target byte order.
+    fn push_u32(&mut self, value: u32) {
+        for b in value.to_le_bytes().iter() {
+            self.push_hex(*b);
+        }
+    }
+}
+
+impl Debugger for Kernel {
+    fn gdb_attach(&mut self, port: usize) -> bool {
+        match self.serial(port) {
+            Some(ref p) if p.present() => {},
+            _ => return false
+        }
+
+        PORT.store(port, Ordering::SeqCst);
+        self.register_trap(DEBUG_TRAP, gdb_trap);
+        self.register_trap(BREAKPOINT_TRAP, gdb_trap);
+        true
+    }
+
+    fn gdb_port(&self) -> Option<usize> {
+        match PORT.load(Ordering::SeqCst) {
+            port if port == NO_PORT => None,
+            port => Some(port)
+        }
+    }
+
+    fn gdb_break() {
+        unsafe { llvm_asm!("int3") };
+    }
+
+    fn gdb_interrupt() {
+        BREAK_PENDING.store(true, Ordering::SeqCst);
+    }
+}
+
+fn gdb_trap(frame: &mut TrapFrame) {
+    // Our own breakpoints report the address of the int3, not the one after.
+    if frame.vector as usize == BREAKPOINT_TRAP && find_breakpoint(frame.eip as usize - 1).is_some() {
+        frame.eip -= 1;
+    }
+
+    // Single-stepping is done for now, until GDB asks for another step.
+    frame.eflags &= !EFLAGS_TF;
+
+    stub(frame, SIGTRAP);
+}
+
+// Called on the way out of every interrupt, to stop where the interrupt
+// came in if GDB has asked us to.
+pub fn break_if_pending(frame: &mut TrapFrame) {
+    if BREAK_PENDING.swap(false, Ordering::SeqCst) {
+        stub(frame, SIGINT);
+    }
+}
+
+fn stub(frame: &mut TrapFrame, signal: u8) {
+    let port = PORT.load(Ordering::SeqCst);
+    if port == NO_PORT {
+        return;
+    }
+
+    let reply = unsafe { &mut REPLY };
+
+    // On the first stop GDB isn't connected yet, and will ask why we stopped
+    // once it is.
+    if RESUMED.swap(false, Ordering::SeqCst) {
+        reply.clear();
+        stop_reply(reply, signal);
+        send_packet(port, reply);
+    }
+
+    loop {
+        let len = receive_packet(port);
+        let packet = unsafe { &PACKET[..len] };
+
+        reply.clear();
+        match handle_packet(frame, packet, signal, reply) {
+            Action::Stay => send_packet(port, reply),
+            Action::Resume => return,
+            Action::ReplyAndResume => {
+                send_packet(port, reply);
+                return;
+            }
+        }
+    }
+}
+
+fn handle_packet(frame: &mut TrapFrame, packet: &[u8], signal: u8, reply: &mut Reply) -> Action {
+    let (&command, args) = match packet.split_first() {
+        Some(split) => split,
+        None => return Action::Stay
+    };
+
+    match command {
+        b'?' => stop_reply(reply, signal),
+        b'g' => {
+            for n in 0..NUM_REGISTERS {
+                reply.push_u32(register(frame, n));
+            }
+        },
+        b'G' => {
+            for n in 0..NUM_REGISTERS {
+                match args.get(n * 8..n * 8 + 8).and_then(parse_u32) {
+                    Some(value) => { set_register(frame, n, value); },
+                    None => break
+                }
+            }
+            reply.push_str("OK");
+        },
+        b'p' => match parse_hex(args) {
+            Some((n, _)) if n < NUM_REGISTERS => reply.push_u32(register(frame, n)),
+            _ => reply.push_str("E00")
+        },
+        b'P' => {
+            let set = parse_hex(args).and_then(|(n, rest)| {
+                let value = parse_u32(rest.strip_prefix(b"=")?)?;
+                Some(set_register(frame, n, value))
+            });
+
+            match set {
+                Some(true) => reply.push_str("OK"),
+                _ => reply.push_str("E00")
+            }
+        },
+        b'm' => match parse_range(args) {
+            Some((addr, len, _)) if readable(addr, len) => {
+                for i in 0..len {
+                    reply.push_hex(unsafe { *((addr + i) as *const u8) });
+                }
+            },
+            _ => reply.push_str("E14")
+        },
+        b'M' => {
+            let written = parse_range(args).and_then(|(addr, len, rest)| {
+                let data = rest.strip_prefix(b":")?;
+                if data.len() != len * 2 || !readable(addr, len) {
+                    return None;
+                }
+
+                for i in 0..len {
+                    write_byte(addr + i, parse_u8(&data[i * 2..i * 2 + 2])?);
+                }
+                Some(())
+            });
+
+            match written {
+                Some(()) => reply.push_str("OK"),
+                None => reply.push_str("E14")
+            }
+        },
+        b'c' | b's' => {
+            if let Some((addr, _)) = parse_hex(args) {
+                frame.eip = addr as u32;
+            }
+
+            if command == b's' {
+                frame.eflags |= EFLAGS_TF;
+            }
+
+            // The stop reply is sent when we next stop, not now.
+            RESUMED.store(true, Ordering::SeqCst);
+            return Action::Resume;
+        },
+        b'Z' | b'z' => {
+            // Only software breakpoints are supported.
+            if let Some(rest) = args.strip_prefix(b"0,") {
+                let done = match parse_hex(rest) {
+                    Some((addr, _)) if command == b'Z' => insert_breakpoint(addr),
+                    Some((addr, _)) => remove_breakpoint(addr),
+                    None => false
+                };
+
+                reply.push_str(if done { "OK" } else { "E0E" });
+            }
+        },
+        b'D' | b'k' => {
+            // Leave things as GDB found them.
+            remove_all_breakpoints();
+            if command == b'k' {
+                return Action::Resume;
+            }
+
+            reply.push_str("OK");
+            return Action::ReplyAndResume;
+        },
+        b'H' => reply.push_str("OK"),
+        b'q' => {
+            if args.starts_with(b"Supported") {
+                reply.push_str("PacketSize=");
+                for b in (PACKET_SIZE as u32).to_be_bytes().iter() {
+                    reply.push_hex(*b);
+                }
+            } else if args == b"Attached" {
+                reply.push_str("1");
+            }
+        },
+        // Anything else gets an empty reply, which tells GDB we don't do it.
+        _ => {}
+    }
+
+    Action::Stay
+}
+
+fn stop_reply(reply: &mut Reply, signal: u8) {
+    reply.push(b'S');
+    reply.push_hex(signal);
+}
+
+// Waits for a packet with a good checksum, acknowledging it, and returns
+// its length. Anything between packets (such as acks or ^C) is ignored.
+fn receive_packet(port: usize) -> usize {
+    let packet = unsafe { &mut PACKET };
+
+    loop {
+        while read_byte(port) != b'$' {}
+
+        let mut len = 0;
+        let mut sum: u8 = 0;
+        let mut overflow = false;
+        loop {
+            let b = read_byte(port);
+            if b == b'#' {
+                break;
+            }
+
+            if len < PACKET_SIZE {
+                packet[len] = b;
+                len += 1;
+            } else {
+                overflow = true;
+            }
+            sum = sum.wrapping_add(b);
+        }
+
+        let check = [read_byte(port), read_byte(port)];
+        if !overflow && parse_u8(&check) == Some(sum) {
+            Kernel::serial_raw_write(port, b'+');
+            return len;
+        }
+
+        Kernel::serial_raw_write(port, b'-');
+    }
+}
+
+// Sends a packet, again and again until GDB acknowledges it.
+fn send_packet(port: usize, reply: &Reply) {
+    let data = &reply.buf[..reply.len];
+    let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+
+    loop {
+        Kernel::serial_raw_write(port, b'$');
+        for b in data {
+            Kernel::serial_raw_write(port, *b);
+        }
+        Kernel::serial_raw_write(port, b'#');
+        Kernel::serial_raw_write(port, hex_digit(sum >> 4));
+        Kernel::serial_raw_write(port, hex_digit(sum & 0xF));
+
+        match read_byte(port) {
+            b'+' => return,
+            _ => {}
+        }
+    }
+}
+
+fn read_byte(port: usize) -> u8 {
+    loop {
+        if let Some(b) = Kernel::serial_raw_read(port) {
+            return b;
+        }
+    }
+}
+
+fn register(frame: &TrapFrame, n: usize) -> u32 {
+    match n {
+        0 => frame.eax,
+        1 => frame.ecx,
+        2 => frame.edx,
+        3 => frame.ebx,
+        4 => interrupted_esp(frame),
+        5 => frame.ebp,
+        6 => frame.esi,
+        7 => frame.edi,
+        8 => frame.eip,
+        9 => frame.eflags,
+        10 => frame.cs,
+        11 => {
+            let mut ss: u32 = 0;
+            unsafe { llvm_asm!("mov %ss, $0" : "=r" (ss)) };
+            ss & 0xFFFF
+        },
+        12 => frame.ds,
+        13 => frame.es,
+        14 => frame.fs,
+        15 => frame.gs,
+        _ => 0
+    }
+}
+
+// The stack pointer and code and stack segments can't be changed from a
+// trap frame, so writes to them fail.
+fn set_register(frame: &mut TrapFrame, n: usize, value: u32) -> bool {
+    let reg = match n {
+        0 => &mut frame.eax,
+        1 => &mut frame.ecx,
+        2 => &mut frame.edx,
+        3 => &mut frame.ebx,
+        5 => &mut frame.ebp,
+        6 => &mut frame.esi,
+        7 => &mut frame.edi,
+        8 => &mut frame.eip,
+        9 => &mut frame.eflags,
+        12 => &mut frame.ds,
+        13 => &mut frame.es,
+        14 => &mut frame.fs,
+        15 => &mut frame.gs,
+        _ => return false
+    };
+
+    *reg = value;
+    true
+}
+
+// Traps taken in ring 0 don't push esp, so the interrupted stack carries on
+// right where the frame ends.
+fn interrupted_esp(frame: &TrapFrame) -> u32 {
+    (frame as *const TrapFrame as usize + core::mem::size_of::<TrapFrame>()) as u32
+}
+
+fn readable(addr: usize, len: usize) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false
+    };
+
+    (addr..end).all(|a| paging::present(a))
+}
+
+fn write_byte(addr: usize, b: u8) {
+    paging::with_writes_allowed(|| unsafe { *(addr as *mut u8) = b });
+}
+
+fn find_breakpoint(addr: usize) -> Option<usize> {
+    unsafe { BREAKPOINTS.iter().position(|bp| bp.map_or(false, |bp| bp.addr == addr)) }
+}
+
+fn insert_breakpoint(addr: usize) -> bool {
+    if find_breakpoint(addr).is_some() {
+        return true;
+    }
+
+    if !readable(addr, 1) {
+        return false;
+    }
+
+    let breakpoints = unsafe { &mut BREAKPOINTS };
+    match breakpoints.iter_mut().find(|bp| bp.is_none()) {
+        Some(slot) => {
+            *slot = Some(Breakpoint{addr: addr, saved: unsafe { *(addr as *const u8) }});
+            write_byte(addr, INT3);
+            true
+        },
+        None => false
+    }
+}
+
+fn remove_breakpoint(addr: usize) -> bool {
+    match find_breakpoint(addr) {
+        Some(index) => {
+            let breakpoints = unsafe { &mut BREAKPOINTS };
+            if let Some(bp) = breakpoints[index].take() {
+                write_byte(bp.addr, bp.saved);
+            }
+            true
+        },
+        None => false
+    }
+}
+
+fn remove_all_breakpoints() {
+    let breakpoints = unsafe { &mut BREAKPOINTS };
+    for slot in breakpoints.iter_mut() {
+        if let Some(bp) = slot.take() {
+            write_byte(bp.addr, bp.saved);
+        }
+    }
+}
+
+fn hex_digit(n: u8) -> u8 {
+    b"0123456789abcdef"[(n & 0xF) as usize]
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None
+    }
+}
+
+fn parse_u8(s: &[u8]) -> Option<u8> {
+    match s {
+        [hi, lo] => Some((hex_value(*hi)? << 4) | hex_value(*lo)?),
+        _ => None
+    }
+}
+
+// A register value, in target byte order.
+fn parse_u32(s: &[u8]) -> Option<u32> {
+    if s.len() != 8 {
+        return None;
+    }
+
+    let mut bytes = [0u8; 4];
+    for i in 0..4 {
+        bytes[i] = parse_u8(&s[i * 2..i * 2 + 2])?;
+    }
+    Some(u32::from_le_bytes(bytes))
+}
+
+// A big-endian hex number, such as an address, and whatever follows it.
+fn parse_hex(s: &[u8]) -> Option<(usize, &[u8])> {
+    let digits = s.iter().take_while(|c| hex_value(**c).is_some()).count();
+    if digits == 0 || digits > 8 {
+        return None;
+    }
+
+    let value = s[..digits].iter().fold(0, |value, c| (value << 4) | hex_value(*c).unwrap() as usize);
+    Some((value, &s[digits..]))
+}
+
+// "addr,length", as used by the memory packets.
+// Lengths are limited to what fits in a packet as hex, which also keeps
+// them small enough to double.
+fn parse_range(s: &[u8]) -> Option<(usize, usize, &[u8])> {
+    let (addr, rest) = parse_hex(s)?;
+    let (len, rest) = parse_hex(rest.strip_prefix(b",")?)?;
+    if len > PACKET_SIZE / 2 {
+        return None;
+    }
+    Some((addr, len, rest))
+}
@@ -14,22 +14,50 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
-use alloc::sync::Arc;
-
-use crate::Kernel;
-use crate::util::sync::Spinlock;
+use super::exceptions;
+use super::gdb;
+use super::threads;
 
 type IdtTable = [IdtEntry; 256];
 
-// One handler per interrupt line.
-type InterruptHandlerList = [InterruptHandler; 256];
-
 // Base for all our IRQ handling.
 extern "C" { fn isrs_base(); fn set_isr_handler(f: usize); }
 
 // Size of the interrupt stub, so we can create our initial IDT easily.
 static ISR_STUB_LENGTH: u32 = 10;
 
+// The first 32 vectors are reserved for CPU exceptions.
+static NUM_EXCEPTIONS: usize = 32;
+
+// Everything lowlevel_isr_entry pushes, lowest address first. Handlers may
+// modify the frame and the changes take effect when the trap returns - with
+// the exception of esp, which popa ignores.
+#[repr(C)]
+pub struct TrapFrame {
+    pub cr2: u32,
+
+    pub gs: u32,
+    pub fs: u32,
+    pub es: u32,
+    pub ds: u32,
+
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    pub esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+
+    pub vector: u32,
+    pub error_code: u32,
+
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+}
+
 #[repr(C, packed)]
 struct IdtRegister {
     limit: u16,
@@ -48,25 +76,28 @@ struct IdtEntry {
 
 #[derive(Copy, Clone)]
 struct InterruptHandler {
-    f: extern "Rust" fn(usize),
+    f: extern "Rust" fn(&mut TrapFrame),
 }
 
+// The handlers live alongside the IDT itself rather than behind the kernel
+// lock, so that an exception raised with the kernel locked still reaches
+// its handler.
 struct InternalIdt {
     table: [IdtEntry; 256],
     reg: IdtRegister,
+    handlers: [InterruptHandler; 256],
 }
 
 static mut IDT: InternalIdt = InternalIdt::new();
 
-pub struct Idt {
-    handlers: Arc<Spinlock<[InterruptHandler; 256]>>
-}
+pub struct Idt;
 
 impl InternalIdt {
     const fn new() -> InternalIdt {
         InternalIdt {
             table: [IdtEntry::new(); 256],
-            reg: IdtRegister::new(0 as *const IdtTable)
+            reg: IdtRegister::new(0 as *const IdtTable),
+            handlers: [InterruptHandler::new(default_trap); 256],
         }
     }
 
@@ -77,6 +108,10 @@ impl InternalIdt {
             base += ISR_STUB_LENGTH;
         }
 
+        for i in 0..NUM_EXCEPTIONS {
+            self.handlers[i] = InterruptHandler::new(exceptions::exception_trap);
+        }
+
         self.reg = IdtRegister::new(&self.table as *const IdtTable);
 
         self.load();
@@ -95,21 +130,17 @@ impl InternalIdt {
 
 impl Idt {
     pub fn new() -> Idt {
-        Idt{handlers: Arc::new(Spinlock::new([InterruptHandler::new(default_trap); 256]))}
+        Idt
     }
 
     pub fn init(&mut self) {
         unsafe { IDT.init(); }
     }
 
-    pub fn register(&mut self, index: usize, handler: extern "Rust" fn(usize)) {
-        let mut handlers = self.handlers.lock().unwrap();
-        handlers[index] = InterruptHandler::new(handler);
-    }
-
-    fn trap(&self, which: usize) -> extern "Rust" fn(usize) {
-        let handlers = self.handlers.lock().unwrap();
-        handlers[which].f
+    pub fn register(&mut self, index: usize, handler: extern "Rust" fn(&mut TrapFrame)) {
+        // Only called with the kernel locked, so interrupts are off and
+        // nothing can be reading the handler while we swap it out.
+        unsafe { IDT.handlers[index] = InterruptHandler::new(handler); }
     }
 }
 
@@ -139,18 +170,27 @@ impl IdtEntry {
 }
 
 impl InterruptHandler {
-    pub fn new(handler: extern "Rust" fn(usize)) -> InterruptHandler {
+    pub const fn new(handler: extern "Rust" fn(&mut TrapFrame)) -> InterruptHandler {
         InterruptHandler{f: handler}
     }
 }
 
 #[no_mangle]
-pub extern "C" fn isr_rustentry(which: usize) {
+pub extern "C" fn isr_rustentry(frame: *mut TrapFrame) {
+    let frame = unsafe { &mut *frame };
+
     // Entry point for IRQ - find if we have a handler configured or not.
-    let f = Kernel::kernel().lock().unwrap().arch.state.idt.trap(which);
-    f(which);
+    let f = unsafe { IDT.handlers[frame.vector as usize].f };
+    f(frame);
+
+    // The IRQ may have asked to stop in the debugger, or used up the
+    // running thread's time slice.
+    if frame.vector as usize >= NUM_EXCEPTIONS {
+        gdb::break_if_pending(frame);
+        threads::preempt_if_pending();
+    }
 }
 
-fn default_trap(_: usize) {
+fn default_trap(_: &mut TrapFrame) {
     // no-op
 }
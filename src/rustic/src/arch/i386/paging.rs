@@ -0,0 +1,233 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use core::ptr;
+
+use crate::Kernel;
+use crate::arch::VirtualMemory;
+use crate::arch::mmu;
+use crate::util::mem;
+
+static PAGE_SIZE: usize = 4096;
+
+// Page directory and page table entry bits.
+static PAGE_PRESENT: u32 = 1 << 0;
+static PAGE_WRITE: u32 = 1 << 1;
+static PAGE_WRITE_THROUGH: u32 = 1 << 3;
+static PAGE_CACHE_DISABLE: u32 = 1 << 4;
+
+static PAGE_ADDRESS_MASK: u32 = 0xFFFFF000;
+
+// CR0 bits: paging enable, and write protect (so that read-only pages are
+// read-only for the kernel too, not just for ring 3).
+static CR0_PG: u32 = 1 << 31;
+static CR0_WP: u32 = 1 << 16;
+
+// Kernel image boundaries, from the linker script (not actually functions).
+extern {
+    fn __init();
+    fn __ro_end();
+}
+
+pub struct Paging {
+    // Physical address of the page directory.
+    directory: usize,
+}
+
+impl Paging {
+    pub fn new() -> Paging {
+        Paging{directory: 0}
+    }
+
+    // Builds the kernel address space and switches paging on. Everything
+    // is identity mapped: low memory (bar the first page, so that null
+    // pointers fault), the kernel image with code and read-only data
+    // write-protected, and then all of RAM above that.
+    pub fn init(&mut self) {
+        self.directory = match alloc_table() {
+            Some(table) => table,
+            None => panic!("no memory for the kernel page directory")
+        };
+
+        let ro_start = __init as usize;
+        let ro_end = __ro_end as usize;
+        let top = mem::memory_top();
+
+        self.identity_map(PAGE_SIZE, ro_start, 0);
+        self.identity_map(ro_start, ro_end, mmu::READ_ONLY);
+        self.identity_map(ro_end, top, 0);
+
+        unsafe {
+            llvm_asm!("mov $0, %cr3" :: "r" (self.directory) : "memory");
+
+            let mut cr0: u32 = 0;
+            llvm_asm!("mov %cr0, $0" : "=r" (cr0));
+            cr0 |= CR0_PG | CR0_WP;
+            llvm_asm!("mov $0, %cr0" :: "r" (cr0) : "memory");
+        }
+    }
+
+    fn identity_map(&mut self, start: usize, end: usize, flags: u32) {
+        for addr in (start..end).step_by(PAGE_SIZE) {
+            if !self.map_page(addr, addr, flags) {
+                panic!("unable to identity map {:#x}", addr);
+            }
+        }
+    }
+
+    fn map_page(&mut self, virt: usize, phys: usize, flags: u32) -> bool {
+        if (virt | phys) & (PAGE_SIZE - 1) != 0 {
+            return false;
+        }
+
+        // There's no execute-disable bit without PAE.
+        if flags & mmu::NO_EXEC != 0 {
+            return false;
+        }
+
+        let pde = unsafe { (self.directory as *mut u32).add(virt >> 22) };
+        let table = if unsafe { *pde } & PAGE_PRESENT == 0 {
+            let table = match alloc_table() {
+                Some(table) => table,
+                None => return false
+            };
+
+            // Directory entries are permissive, the page table entries do
+            // the actual restricting.
+            unsafe { *pde = (table as u32) | PAGE_PRESENT | PAGE_WRITE };
+            table
+        } else {
+            (unsafe { *pde } & PAGE_ADDRESS_MASK) as usize
+        };
+
+        let pte = unsafe { (table as *mut u32).add((virt >> 12) & 0x3FF) };
+        if unsafe { *pte } & PAGE_PRESENT != 0 {
+            return false;
+        }
+
+        let mut entry = (phys as u32) | PAGE_PRESENT;
+        if flags & mmu::READ_ONLY == 0 {
+            entry |= PAGE_WRITE;
+        }
+
+        if flags & mmu::NO_CACHE != 0 {
+            entry |= PAGE_CACHE_DISABLE | PAGE_WRITE_THROUGH;
+        }
+
+        unsafe { *pte = entry };
+        invalidate(virt);
+        true
+    }
+
+    fn unmap_page(&mut self, virt: usize) {
+        if let Some(pte) = self.entry(virt) {
+            unsafe { *pte = 0 };
+            invalidate(virt);
+        }
+    }
+
+    fn translate(&self, virt: usize) -> Option<usize> {
+        match self.entry(virt) {
+            Some(pte) => {
+                let frame = (unsafe { *pte } & PAGE_ADDRESS_MASK) as usize;
+                Some(frame | (virt & (PAGE_SIZE - 1)))
+            },
+            None => None
+        }
+    }
+
+    // Finds the page table entry for a present page.
+    fn entry(&self, virt: usize) -> Option<*mut u32> {
+        let pde = unsafe { *(self.directory as *const u32).add(virt >> 22) };
+        if pde & PAGE_PRESENT == 0 {
+            return None;
+        }
+
+        let table = (pde & PAGE_ADDRESS_MASK) as usize;
+        let pte = unsafe { (table as *mut u32).add((virt >> 12) & 0x3FF) };
+        if unsafe { *pte } & PAGE_PRESENT == 0 {
+            None
+        } else {
+            Some(pte)
+        }
+    }
+}
+
+impl VirtualMemory for Kernel {
+    fn map(&mut self, virt: usize, phys: usize, flags: u32) -> bool {
+        self.arch.state.paging.map_page(virt, phys, flags)
+    }
+
+    fn unmap(&mut self, virt: usize) {
+        self.arch.state.paging.unmap_page(virt)
+    }
+
+    fn translate(&self, virt: usize) -> Option<usize> {
+        self.arch.state.paging.translate(virt)
+    }
+
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+}
+
+// Whether an address can be accessed right now, going by the live page
+// tables rather than the kernel's copy - so it's safe to call from a trap
+// handler, where the kernel may be locked.
+pub fn present(virt: usize) -> bool {
+    let mut cr0: u32 = 0;
+    let mut cr3: u32 = 0;
+    unsafe {
+        llvm_asm!("mov %cr0, $0" : "=r" (cr0));
+        llvm_asm!("mov %cr3, $0" : "=r" (cr3));
+    }
+
+    if cr0 & CR0_PG == 0 {
+        return true;
+    }
+
+    let pde = unsafe { *((cr3 & PAGE_ADDRESS_MASK) as *const u32).add(virt >> 22) };
+    if pde & PAGE_PRESENT == 0 {
+        return false;
+    }
+
+    let pte = unsafe { *((pde & PAGE_ADDRESS_MASK) as *const u32).add((virt >> 12) & 0x3FF) };
+    pte & PAGE_PRESENT != 0
+}
+
+// Runs f with write protection off, so that read-only pages (such as the
+// kernel's code) can be written - for planting breakpoints, say.
+pub fn with_writes_allowed<F: FnOnce()>(f: F) {
+    let mut cr0: u32 = 0;
+    unsafe { llvm_asm!("mov %cr0, $0" : "=r" (cr0)) };
+    unsafe { llvm_asm!("mov $0, %cr0" :: "r" (cr0 & !CR0_WP) : "memory") };
+
+    f();
+
+    unsafe { llvm_asm!("mov $0, %cr0" :: "r" (cr0) : "memory") };
+}
+
+// Page tables live in identity mapped RAM, so they are always reachable at
+// their physical address.
+fn alloc_table() -> Option<usize> {
+    let table = mem::alloc_frame()?;
+    unsafe { ptr::write_bytes(table as *mut u8, 0, PAGE_SIZE) };
+    Some(table)
+}
+
+fn invalidate(virt: usize) {
+    unsafe { llvm_asm!("invlpg ($0)" :: "r" (virt) : "memory") };
+}
@@ -0,0 +1,443 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::util::sync::Spinlock;
+use crate::util::time::Instant;
+
+use crate::arch::{Architecture, JoinHandle, JoinPacket, Scheduling, Threads, ThreadSpawn};
+use crate::arch::threads::{self, ThreadInfo};
+
+use crate::Kernel;
+
+use super::State;
+
+static THREAD_STACK_SIZE: usize = 4096;
+
+// Set when the running thread's time slice runs out, and acted upon once
+// the interrupt that noticed has been handled.
+static PREEMPT_PENDING: AtomicBool = AtomicBool::new(false);
+
+// Copies of the uptime (wrapping after 49 days) and the running thread, for
+// code that can't lock the kernel to ask.
+static UPTIME: AtomicUsize = AtomicUsize::new(0);
+static RUNNING_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+// Wakes from thread_wake_deferred(), waiting for the scheduler.
+static DEFERRED_WAKES: Spinlock<VecDeque<usize>> = Spinlock::new(VecDeque::new());
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct ThreadState {
+    edi: u32,
+    esi: u32,
+    ebx: u32,
+    ebp: u32,
+    esp: u32,
+    eip: u32,
+}
+
+pub struct Thread {
+    id: usize,
+    exec_state: ThreadState,
+
+    // Zero for the boot thread, which runs on the stack set up in _start.
+    stack: usize,
+
+    // Set if thread_wake() is called while the thread is not blocked, so
+    // that its next thread_block() returns straight away.
+    wake_pending: bool,
+}
+
+// What happens to the running thread when we switch away from it.
+enum Outgoing {
+    Ready,
+    Blocked,
+    Dead,
+}
+
+// Everything a new thread needs, handed to its trampoline.
+struct ThreadData<F, T> {
+    entry: F,
+    packet: Arc<Spinlock<JoinPacket<T>>>,
+}
+
+// External variable in assembly code (not actually a function)
+extern { fn thread_trampoline(); }
+
+extern "C" {
+    fn save_state(state: *mut ThreadState) -> u32;
+    fn restore_state(state: *const ThreadState) -> u32;
+}
+
+impl Thread {
+    pub fn new(id: usize) -> Thread {
+        Thread{
+            id: id,
+            exec_state: ThreadState::new(),
+            stack: 0,
+            wake_pending: false
+        }
+    }
+}
+
+impl ThreadState {
+    fn new() -> ThreadState {
+        ThreadState{
+            edi: 0,
+            esi: 0,
+            ebx: 0,
+            ebp: 0,
+            esp: 0,
+            eip: 0,
+        }
+    }
+}
+
+impl<F, T> ThreadSpawn<F, T> for Kernel
+where
+    F: FnOnce() -> T,
+    F: Send + 'static,
+    T: Send + 'static
+{
+    fn spawn_thread(&mut self, f: F) -> JoinHandle<T> {
+        let id = self.arch.state.next_thread_id;
+        self.arch.state.next_thread_id += 1;
+
+        let packet = Arc::new(Spinlock::new(JoinPacket::new()));
+        let data = Box::new(ThreadData{entry: f, packet: Arc::clone(&packet)});
+        let trampoline: RustThreadTrampoline = rust_spawned_trampoline::<F, T>;
+
+        let mut new_thread = Thread::new(id);
+        new_thread.exec_state.eip = thread_trampoline as u32;
+        new_thread.exec_state.ebx = trampoline as u32;
+        new_thread.exec_state.esi = Box::into_raw(data) as *mut () as u32;
+
+        let stack = unsafe { alloc(stack_layout()) };
+        if stack.is_null() {
+            panic!("out of memory allocating a thread stack");
+        }
+
+        new_thread.stack = stack as usize;
+        new_thread.exec_state.esp = (new_thread.stack + THREAD_STACK_SIZE) as u32;
+
+        self.arch.state.ready_threads.push_front(new_thread);
+
+        JoinHandle::new(id, packet)
+    }
+}
+
+impl Threads for Kernel {
+    fn thread_terminate() -> ! {
+        // The Arc would never be dropped as we never return, so borrow the
+        // singleton's copy instead.
+        let locked_kernel = Kernel::kernel();
+        let lock = unsafe { &*Arc::as_ptr(&locked_kernel) };
+        drop(locked_kernel);
+
+        switch_threads(lock, Outgoing::Dead);
+
+        panic!("terminated thread was scheduled again");
+    }
+
+    fn reschedule(lock: Arc<Spinlock<Kernel>>) {
+        switch_threads(&lock, Outgoing::Ready);
+    }
+
+    fn thread_id(&self) -> usize {
+        match self.arch.state.running_thread {
+            Some(ref thread) => thread.id,
+            None => panic!("no running thread")
+        }
+    }
+
+    fn thread_list(&self) -> Vec<ThreadInfo> {
+        let state = &self.arch.state;
+        let info = |thread: &Thread, what| ThreadInfo{id: thread.id, state: what};
+
+        let mut list = Vec::new();
+        list.extend(state.running_thread.iter().map(|t| info(t, threads::ThreadState::Running)));
+        list.extend(state.ready_threads.iter().map(|t| info(t, threads::ThreadState::Ready)));
+        list.extend(state.blocked_threads.iter().map(|t| {
+            if state.sleeping.iter().any(|&(_, id)| id == t.id) {
+                info(t, threads::ThreadState::Sleeping)
+            } else {
+                info(t, threads::ThreadState::Blocked)
+            }
+        }));
+
+        list
+    }
+
+    fn thread_block(lock: Arc<Spinlock<Kernel>>) {
+        switch_threads(&lock, Outgoing::Blocked);
+    }
+
+    fn thread_wake(&mut self, id: usize) {
+        let state = &mut self.arch.state;
+
+        if let Some(index) = state.blocked_threads.iter().position(|t| t.id == id) {
+            let thread = state.blocked_threads.remove(index).unwrap();
+            state.ready_threads.push_back(thread);
+            return;
+        }
+
+        // Not blocked (yet) - make sure it doesn't block when it tries to.
+        let not_blocked = state.running_thread.iter_mut().chain(state.ready_threads.iter_mut());
+        for thread in not_blocked {
+            if thread.id == id {
+                thread.wake_pending = true;
+            }
+        }
+    }
+
+    fn thread_wake_deferred(&self, id: usize) {
+        DEFERRED_WAKES.lock().unwrap().push_back(id);
+    }
+
+    fn set_scheduling(&mut self, mode: Scheduling) {
+        self.arch.state.time_slice = match mode {
+            Scheduling::Cooperative => 0,
+            Scheduling::Preemptive(slice) => slice,
+        };
+
+        self.arch.state.slice_remaining = self.arch.state.time_slice;
+    }
+
+    fn scheduler_tick(&mut self, ms: usize) {
+        run_deferred_wakes(self);
+
+        self.arch.state.uptime += ms as u64;
+        UPTIME.store(self.arch.state.uptime as usize, Ordering::SeqCst);
+
+        let now = self.now();
+        while let Some(&(deadline, id)) = self.arch.state.sleeping.front() {
+            if deadline > now {
+                break;
+            }
+
+            self.arch.state.sleeping.pop_front();
+            self.thread_wake(id);
+        }
+
+        let state = &mut self.arch.state;
+        if state.time_slice == 0 {
+            return;
+        }
+
+        if state.slice_remaining > ms {
+            state.slice_remaining -= ms;
+        } else {
+            state.slice_remaining = state.time_slice;
+            PREEMPT_PENDING.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn now(&self) -> Instant {
+        Instant::from_ms(self.arch.state.uptime)
+    }
+
+    fn now_static() -> Instant {
+        Instant::from_ms(UPTIME.load(Ordering::SeqCst) as u64)
+    }
+
+    fn thread_id_static() -> usize {
+        RUNNING_THREAD.load(Ordering::SeqCst)
+    }
+
+    fn thread_block_until(lock: Arc<Spinlock<Kernel>>, deadline: Instant) {
+        let mut kernel = lock.lock().unwrap();
+        if kernel.now() >= deadline {
+            return;
+        }
+
+        let id = kernel.thread_id();
+        let sleeping = &mut kernel.arch.state.sleeping;
+        let index = sleeping.iter().position(|&(d, _)| d > deadline).unwrap_or(sleeping.len());
+        sleeping.insert(index, (deadline, id));
+        drop(kernel);
+
+        Kernel::thread_block(Arc::clone(&lock));
+
+        // We may have been woken early by someone else, in which case our
+        // entry is still in the queue.
+        lock.lock().unwrap().arch.state.sleeping.retain(|&(_, t)| t != id);
+    }
+
+    fn sleep_until(lock: Arc<Spinlock<Kernel>>, deadline: Instant) {
+        while lock.lock().unwrap().now() < deadline {
+            Kernel::thread_block_until(Arc::clone(&lock), deadline);
+        }
+    }
+
+    fn sleep_ms(lock: Arc<Spinlock<Kernel>>, ms: usize) {
+        let deadline = lock.lock().unwrap().now() + Duration::from_millis(ms as u64);
+        Kernel::sleep_until(lock, deadline);
+    }
+}
+
+// Called at the end of every IRQ. If the running thread has used up its time
+// slice, we switch threads right here. Everything the interrupted thread
+// needs is already saved in the trap frame on its own stack, so when it is
+// switched back to it simply returns out of this call, back through the
+// interrupt path and into whatever it was doing.
+pub fn preempt_if_pending() {
+    if PREEMPT_PENDING.swap(false, Ordering::SeqCst) {
+        Kernel::reschedule(Kernel::kernel());
+    }
+}
+
+fn run_deferred_wakes(kernel: &mut Kernel) {
+    let wakes = core::mem::replace(&mut *DEFERRED_WAKES.lock().unwrap(), VecDeque::new());
+    for id in wakes {
+        kernel.thread_wake(id);
+    }
+}
+
+// Switches from the running thread to the next ready thread, with the
+// running thread going wherever `outgoing` says.
+fn switch_threads(lock: &Spinlock<Kernel>, outgoing: Outgoing) {
+    // Saved here (rather than relying on the lock) as it's this thread's
+    // interrupt state we need to restore once we're switched back to.
+    let interrupts = Kernel::get_interrupts_static();
+
+    // We wrap the guard in a ManuallyDrop to avoid it being dropped
+    // in the code paths here that run without a lock (in particular,
+    // save_state will return twice).
+    let guard = lock.lock().unwrap();
+    let mut obj = ManuallyDrop::new(guard);
+
+    // Dead threads are never the running thread, so their stacks are safe
+    // to clean up now.
+    reap_threads(&mut obj.arch.state);
+    run_deferred_wakes(&mut obj);
+
+    loop {
+        let state = &mut obj.arch.state;
+
+        // Only blocking uses up a wake. A thread that's just rescheduled
+        // (or preempted) on its way to blocking must still find it there.
+        let wake_pending = match state.running_thread {
+            Some(ref mut thread) if matches!(outgoing, Outgoing::Blocked) => core::mem::replace(&mut thread.wake_pending, false),
+            Some(_) => false,
+            None => panic!("no running thread")
+        };
+
+        match outgoing {
+            Outgoing::Ready if state.ready_threads.is_empty() => {
+                unsafe { ManuallyDrop::drop(&mut obj) };
+                return;
+            },
+            Outgoing::Blocked if wake_pending => {
+                unsafe { ManuallyDrop::drop(&mut obj) };
+                return;
+            },
+            _ => {}
+        }
+
+        if !state.ready_threads.is_empty() {
+            break;
+        }
+
+        // Nothing else can run. Wait for an interrupt to change that (this
+        // thread is still the running thread while we do so).
+        unsafe { ManuallyDrop::drop(&mut obj) };
+        Kernel::wait_for_event_static();
+        obj = ManuallyDrop::new(lock.lock().unwrap());
+    }
+
+    let state = &mut obj.arch.state;
+    let old_thread = state.running_thread.take().unwrap();
+    let new_thread = state.ready_threads.pop_front().unwrap();
+    let new_state = new_thread.exec_state;
+    RUNNING_THREAD.store(new_thread.id, Ordering::SeqCst);
+    state.running_thread = Some(new_thread);  // move into the Option
+    state.slice_remaining = state.time_slice;
+
+    let queue = match outgoing {
+        Outgoing::Ready => &mut state.ready_threads,
+        Outgoing::Blocked => &mut state.blocked_threads,
+        Outgoing::Dead => &mut state.dead_threads,
+    };
+    queue.push_back(old_thread);
+
+    if let Outgoing::Dead = outgoing {
+        // Nothing to save - this thread never runs again.
+    } else {
+        let old_state = &mut queue.back_mut().unwrap().exec_state as *mut ThreadState;
+        if unsafe { save_state(old_state) } == 1 {
+            // Just got context-switched to.
+            Kernel::set_interrupts_static(interrupts);
+            return;
+        }
+    }
+
+    // Unlock right before we load the new context, but leave interrupts off
+    // until we're actually running on the new thread's stack.
+    unsafe {
+        lock.force_unlock();
+        restore_state(&new_state);
+    }
+
+    // unreachable
+    loop {}
+}
+
+fn reap_threads(state: &mut State) {
+    while let Some(thread) = state.dead_threads.pop_front() {
+        if thread.stack != 0 {
+            unsafe { dealloc(thread.stack as *mut u8, stack_layout()) };
+        }
+    }
+}
+
+fn stack_layout() -> Layout {
+    Layout::from_size_align(THREAD_STACK_SIZE, 16).unwrap()
+}
+
+pub type RustThreadTrampoline = unsafe extern "C" fn(*mut c_void) -> !;
+
+pub unsafe extern "C" fn rust_spawned_trampoline<F, T>(data: *mut c_void) -> !
+where
+    F: FnOnce() -> T,
+    F: Send + 'static,
+    T: Send + 'static
+{
+    let data = Box::from_raw(data as *mut ThreadData<F, T>);
+    let ThreadData{entry, packet} = *data;
+
+    let result = entry();
+
+    // Hand the result over to the JoinHandle, and wake up whoever is
+    // waiting on it.
+    let waiter = packet.lock().unwrap().complete(result);
+    drop(packet);
+
+    if let Some(id) = waiter {
+        Kernel::kernel().lock().unwrap().thread_wake(id);
+    }
+
+    Kernel::thread_terminate()
+}
@@ -15,7 +15,9 @@
  */
 
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use crate::util::sync::Spinlock;
+use crate::util::time::Instant;
 use crate::Kernel;
 
 #[cfg(feature="arch_i386")]
@@ -30,10 +32,15 @@ mod armv7;
 // State module pulls in architecture-specific state type as 'State' type.
 mod state;
 
+// Register state saved when a trap is taken, which trap handlers receive.
+pub use self::state::TrapFrame;
+
 pub trait Architecture {
     fn arch_initialise(&mut self) -> bool;
 
-    fn register_trap(&mut self, num: usize, f: extern "Rust" fn(usize));
+    // Handlers can inspect and modify the interrupted register state. By
+    // default, CPU exceptions dump the trap frame and panic.
+    fn register_trap(&mut self, num: usize, f: extern "Rust" fn(&mut TrapFrame));
 
     // TODO: while 'self' is protected by a Spinlock, these are essentially
     // useless functions as get_interrupts() should always be false and
@@ -47,15 +54,178 @@ pub trait Architecture {
     fn wait_for_event_static();
 }
 
-pub trait ThreadSpawn<F> {
-    fn spawn_thread(&mut self, f: F);
+pub mod mmu {
+    // Flags for VirtualMemory::map. Mappings are writable and executable
+    // unless asked otherwise.
+    pub const READ_ONLY: u32 = 1 << 0;
+    // Not every architecture can do this (i386 can't without PAE), in which
+    // case map() fails rather than leave the page executable.
+    pub const NO_EXEC: u32 = 1 << 1;
+    pub const NO_CACHE: u32 = 1 << 2;
+}
+
+pub trait VirtualMemory {
+    // Maps a single page. Both addresses must be page aligned. Returns false
+    // if the page is already mapped, a page table couldn't be allocated or
+    // the flags can't be honoured.
+    fn map(&mut self, virt: usize, phys: usize, flags: u32) -> bool;
+    fn unmap(&mut self, virt: usize);
+    fn translate(&self, virt: usize) -> Option<usize>;
+
+    fn page_size(&self) -> usize;
+}
+
+pub trait Debugger {
+    // Hands the breakpoint and debug traps to a GDB remote stub talking on
+    // the given serial port. Nothing else should use the port afterwards.
+    // Returns false if the port isn't there.
+    fn gdb_attach(&mut self, port: usize) -> bool;
+    fn gdb_port(&self) -> Option<usize>;
+
+    // Stops in the debugger, as though a breakpoint had been hit. Call this
+    // after gdb_attach() to wait for GDB to connect.
+    fn gdb_break();
+
+    // Stops in the debugger once the current interrupt handler returns.
+    // Used by the machine layer when GDB asks to stop a running system.
+    fn gdb_interrupt();
+}
+
+pub mod threads {
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum ThreadState {
+        Running,
+        Ready,
+        Blocked,
+        // Blocked, but with a deadline to wake up at.
+        Sleeping,
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    pub struct ThreadInfo {
+        pub id: usize,
+        pub state: ThreadState,
+    }
+}
+
+pub trait ThreadSpawn<F, T> {
+    fn spawn_thread(&mut self, f: F) -> JoinHandle<T>;
+}
+
+pub enum Scheduling {
+    // Threads run until they reschedule, block or terminate.
+    Cooperative,
+    // As above, but threads are also switched out when they have run for
+    // the given time slice (in milliseconds).
+    Preemptive(usize),
 }
 
 pub trait Threads {
-    fn thread_terminate(&mut self) -> !;
+    // Ends the calling thread. Its stack is freed once another thread is
+    // running. Threads that return from their entry point end up here.
+    fn thread_terminate() -> !;
 
     // Trigger a reschedule.
     fn reschedule(lock: Arc<Spinlock<Kernel>>);
+
+    fn thread_id(&self) -> usize;
+
+    // Every thread that hasn't terminated, in no particular order.
+    fn thread_list(&self) -> Vec<threads::ThreadInfo>;
+
+    // Parks the calling thread until thread_wake() is called for it. A wake
+    // that arrives before the thread gets around to blocking is not lost -
+    // the block returns immediately instead. Wakes can be spurious, so
+    // callers should re-check whatever they were waiting for.
+    fn thread_block(lock: Arc<Spinlock<Kernel>>);
+    fn thread_wake(&mut self, id: usize);
+
+    // As thread_wake(), for code that only has the kernel shared. The wake
+    // happens at the next scheduler tick or thread switch.
+    fn thread_wake_deferred(&self, id: usize);
+
+    // As thread_block(), but the thread is also woken once the deadline
+    // passes.
+    fn thread_block_until(lock: Arc<Spinlock<Kernel>>, deadline: Instant);
+
+    // Cooperative scheduling is the default.
+    fn set_scheduling(&mut self, mode: Scheduling);
+
+    // Called by the machine layer from its timer interrupt, with the time
+    // since the last tick.
+    fn scheduler_tick(&mut self, ms: usize);
+
+    // Time since the first scheduler tick.
+    fn now(&self) -> Instant;
+
+    // As now() and thread_id(), without needing the kernel locked.
+    fn now_static() -> Instant;
+    fn thread_id_static() -> usize;
+
+    // Parks the calling thread until at least the given time. Must not be
+    // called with the kernel locked.
+    fn sleep_until(lock: Arc<Spinlock<Kernel>>, deadline: Instant);
+    fn sleep_ms(lock: Arc<Spinlock<Kernel>>, ms: usize);
+}
+
+// Shared between a thread and its JoinHandle.
+pub struct JoinPacket<T> {
+    result: Option<T>,
+    finished: bool,
+    waiter: Option<usize>,
+}
+
+pub struct JoinHandle<T> {
+    thread: usize,
+    packet: Arc<Spinlock<JoinPacket<T>>>,
+}
+
+impl<T> JoinPacket<T> {
+    pub fn new() -> JoinPacket<T> {
+        JoinPacket{result: None, finished: false, waiter: None}
+    }
+
+    // Stores the thread's return value, and returns the thread waiting for
+    // it (if any) so it can be woken.
+    pub fn complete(&mut self, result: T) -> Option<usize> {
+        self.result = Some(result);
+        self.finished = true;
+        self.waiter.take()
+    }
+}
+
+impl<T> JoinHandle<T> {
+    pub fn new(thread: usize, packet: Arc<Spinlock<JoinPacket<T>>>) -> JoinHandle<T> {
+        JoinHandle{thread: thread, packet: packet}
+    }
+
+    pub fn thread_id(&self) -> usize {
+        self.thread
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.lock().unwrap().finished
+    }
+
+    // Blocks until the thread returns, and then hands back its return value.
+    // Must not be called with the kernel locked.
+    pub fn join(self) -> T {
+        let kernel = Kernel::kernel();
+
+        loop {
+            let me = kernel.lock().unwrap().thread_id();
+
+            let mut packet = self.packet.lock().unwrap();
+            if let Some(result) = packet.result.take() {
+                return result;
+            }
+
+            packet.waiter = Some(me);
+            drop(packet);
+
+            Kernel::thread_block(Arc::clone(&kernel));
+        }
+    }
 }
 
 pub trait TrapHandler {
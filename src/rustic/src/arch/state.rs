@@ -15,4 +15,4 @@
  */
 
 #[cfg(feature="arch_i386")]
-pub use super::i386::State;
+pub use super::i386::{State, TrapFrame};
@@ -26,8 +26,8 @@
 extern crate alloc;
 
 // Publish the main things users care about.
-pub use mach::{Machine, TimerHandlers, Mmio, Gpio, IoPort, IrqHandler, Serial};
-pub use arch::{Architecture, Threads, ThreadSpawn};
+pub use mach::{Machine, TimerHandlers, Mmio, Gpio, IoPort, IrqHandler, Serial, SerialPorts, PhysicalMemory};
+pub use arch::{Architecture, Debugger, Scheduling, Threads, ThreadSpawn, VirtualMemory};
 
 // Pull in the architectural layer (CPU etc).
 pub mod arch;
@@ -45,7 +45,7 @@ use util::sync::Spinlock;
 
 static mut KERNEL_SINGLETON: Option<Arc<Spinlock<Kernel>>> = None;
 
-struct Debug {
+pub(crate) struct Debug {
 }
 
 impl Write for Debug {
@@ -87,6 +87,12 @@ impl Kernel {
             panic!("Kernel::start called more than once!");
         }
 
+        // Nothing can be allocated until we know where physical memory is.
+        Kernel::init_physical_memory();
+
+        // Logging needs the heap, and everything after this might log.
+        util::logging::init();
+
         let mut kernel = Kernel {
             mach: mach::create(),
             arch: arch::create()
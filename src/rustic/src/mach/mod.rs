@@ -18,6 +18,7 @@ use crate::util::colour;
 
 use alloc::sync::Arc;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 use crate::Kernel;
 use crate::util::sync::Spinlock;
@@ -35,6 +36,9 @@ mod rpi;
 // private type for the relevant target machine.
 mod state;
 
+// Machine-specific handle to a serial port.
+pub use self::state::SerialPort;
+
 pub mod parity {
     pub enum Parity {
         NoParity,
@@ -45,11 +49,130 @@ pub mod parity {
     }
 }
 
+pub mod flow {
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum FlowControl {
+        NoFlowControl,
+        RtsCts,
+        XonXoff
+    }
+}
+
+pub mod keys {
+    // Bits in KeyEvent::modifiers.
+    pub const SHIFT: u8 = 1 << 0;
+    pub const CTRL: u8 = 1 << 1;
+    pub const ALT: u8 = 1 << 2;
+    pub const CAPS_LOCK: u8 = 1 << 3;
+    pub const NUM_LOCK: u8 = 1 << 4;
+    pub const SCROLL_LOCK: u8 = 1 << 5;
+    // Right Alt, which selects a layout's third level.
+    pub const ALT_GR: u8 = 1 << 6;
+
+    // Keycodes are PC scan code set 1 make codes, with the prefix byte in
+    // the top half for keys that have one. Other machines translate to
+    // these. Extended keys that apps commonly care about:
+    pub const KEYPAD_ENTER: u16 = 0xE01C;
+    pub const RIGHT_CTRL: u16 = 0xE01D;
+    pub const KEYPAD_SLASH: u16 = 0xE035;
+    pub const RIGHT_ALT: u16 = 0xE038;
+    pub const HOME: u16 = 0xE047;
+    pub const UP: u16 = 0xE048;
+    pub const PAGE_UP: u16 = 0xE049;
+    pub const LEFT: u16 = 0xE04B;
+    pub const RIGHT: u16 = 0xE04D;
+    pub const END: u16 = 0xE04F;
+    pub const DOWN: u16 = 0xE050;
+    pub const PAGE_DOWN: u16 = 0xE051;
+    pub const INSERT: u16 = 0xE052;
+    pub const DELETE: u16 = 0xE053;
+    pub const PAUSE: u16 = 0xE11D;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum Layout {
+        Us,
+        Uk,
+        De,
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct KeyEvent {
+        // Which physical key (see above).
+        pub keycode: u16,
+        pub pressed: bool,
+        // Modifiers in effect as of this event.
+        pub modifiers: u8,
+        // What the key types, if anything, taking modifiers into account.
+        // Only set for presses.
+        pub ch: Option<char>,
+    }
+}
+
+pub mod mouse {
+    // Button bits in MouseEvent.
+    pub const LEFT: u8 = 1 << 0;
+    pub const RIGHT: u8 = 1 << 1;
+    pub const MIDDLE: u8 = 1 << 2;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct MouseEvent {
+        // Relative motion, with positive dy being down the screen.
+        pub dx: i32,
+        pub dy: i32,
+        // Wheel clicks, positive towards the user. Always zero without a
+        // wheel.
+        pub wheel: i32,
+        // Buttons held down, and those that changed since the last event.
+        pub buttons: u8,
+        pub changed: u8,
+    }
+}
+
+pub mod irq {
+    // An IRQ with a handler registered.
+    #[derive(Copy, Clone, Debug)]
+    pub struct IrqInfo {
+        pub irq: usize,
+        // Registered with register_irq(), to run on the IRQ thread.
+        pub deferred: bool,
+        // Registered with register_irq_immediate().
+        pub immediate: bool,
+        pub level_trigger: bool,
+    }
+}
+
+pub mod serial {
+    // Modem status lines, as returned by SerialPort::modem_status().
+    pub const CTS: u8 = 0x10;
+    pub const DSR: u8 = 0x20;
+    pub const RI: u8 = 0x40;
+    pub const DCD: u8 = 0x80;
+    pub const MODEM_LINES: u8 = CTS | DSR | RI | DCD;
+
+    // Counts of receive errors since boot.
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+    pub struct SerialErrors {
+        // Bytes lost because they weren't read from the UART in time.
+        pub overrun: usize,
+        pub parity: usize,
+        pub framing: usize,
+        pub breaks: usize,
+        // Bytes lost because the receive buffer was full.
+        pub dropped: usize,
+        // Bytes not sent because the other end stopped us and the transmit
+        // buffer filled up.
+        pub tx_dropped: usize,
+    }
+}
+
 pub trait Machine {
     fn mach_initialise(&mut self) -> bool;
 
     // Static method to debug using whatever means necessary.
     fn debug(msg: &str);
+
+    // Resets the machine.
+    fn reboot(&self) -> !;
 }
 
 pub trait IrqController {
@@ -69,19 +192,54 @@ pub trait IrqHandler {
 
 pub trait IrqRegister{
     fn register_irq(&mut self, irq: usize, handler: extern "Rust" fn(usize), level_trigger: bool);
+
+    // Registers a handler that runs straight from the interrupt, with the
+    // kernel locked, for devices that can't wait for the IRQ thread. It must
+    // be quick and must not block. If the IRQ also has a handler registered
+    // with register_irq(), that runs afterwards on the IRQ thread.
+    fn register_irq_immediate(&mut self, irq: usize, handler: extern "Rust" fn(&mut Kernel, usize));
+
+    // Every IRQ with a handler, lowest first.
+    fn irq_handlers(&self) -> Vec<irq::IrqInfo>;
 }
 
 pub trait Keyboard {
-    fn kb_init(&mut self);
+    // Returns false if there's no keyboard.
+    fn kb_init(&mut self) -> bool;
     fn kb_leds(&mut self, state: u8);
+
+    // Selects how keys are translated into characters. US by default.
+    fn kb_layout(&mut self, layout: keys::Layout);
+
+    // Blocks until the next key event. Must not be called with the kernel
+    // locked.
+    fn read_key() -> keys::KeyEvent;
+
+    // The next key event, if there is one waiting.
+    fn poll_key() -> Option<keys::KeyEvent>;
+}
+
+pub trait Mouse {
+    // Returns false if there's no mouse.
+    fn mouse_init(&mut self) -> bool;
+
+    // Blocks until the mouse moves or a button changes. Must not be called
+    // with the kernel locked.
+    fn read_mouse() -> mouse::MouseEvent;
+
+    fn poll_mouse() -> Option<mouse::MouseEvent>;
 }
 
 pub trait HardwareTimer {
     fn init_timers(&mut self, freq: usize);
+
+    // The tick rate passed to init_timers(), in hertz.
+    fn timer_hz(&self) -> usize;
 }
 
 pub trait TimerHandlers {
     fn register_timer(&mut self, f: extern "Rust" fn(&mut Kernel, usize));
+    fn timers(&self) -> Vec<extern "Rust" fn(&mut Kernel, usize)>;
     fn timer_fired(&mut self, ticks: usize);
 }
 
@@ -97,14 +255,55 @@ pub trait IoPort {
 
 pub trait Serial {
     fn serial_config(&self, baud: i32, data_bits: i32, parity: parity::Parity, stop_bits: i32);
+
+    // Writes are buffered, and only wait if the buffer is full.
     fn serial_write(&self, s: &str);
-    fn serial_read_char(&self) -> char;
     fn serial_write_char(&self, c: char);
+    fn serial_write_bytes(&self, bytes: &[u8]);
+
+    // Spins until a byte arrives - prefer serial_read().
+    fn serial_read_char(&self) -> char;
+
+    // The next received byte, if there is one.
+    fn serial_try_read(&self) -> Option<u8>;
+
+    // Blocks the calling thread until a byte arrives. Must not be called
+    // with the kernel locked.
+    fn serial_read() -> u8;
+
+    fn serial_errors(&self) -> serial::SerialErrors;
+}
+
+// The Serial trait acts on the debug port. This gives access to all of them.
+pub trait SerialPorts {
+    // None if there's no such port. Ports that don't exist on this machine
+    // can still be returned, but do nothing.
+    fn serial(&self, port: usize) -> Option<SerialPort<'_>>;
+
+    // Blocks the calling thread until a byte arrives on the given port. Must
+    // not be called with the kernel locked. None if there's no such port.
+    fn serial_read_from(port: usize) -> Option<u8>;
+
+    // Blocks the calling thread until the modem status lines on the given
+    // port change, returning the new state. Must not be called with the
+    // kernel locked. None if there's no such port.
+    fn serial_wait_modem(port: usize) -> Option<u8>;
+
+    // These go straight to the UART, without the kernel lock or any
+    // buffering, so they can be used from trap handlers. Bytes the port's
+    // interrupt handler has already taken are not seen by serial_raw_read().
+    fn serial_raw_write(port: usize, byte: u8);
+    fn serial_raw_read(port: usize) -> Option<u8>;
+
+    // Picks the port used by debug() and the Serial trait. Returns false if
+    // the port isn't there.
+    fn set_debug_port(&mut self, port: usize) -> bool;
+    fn debug_port(&self) -> usize;
 }
 
 pub trait Screen {
-    fn screen_clear(&self);
-    fn screen_fill(&self, with: char);
+    fn screen_clear(&mut self);
+    fn screen_fill(&mut self, with: char);
 
     fn screen_cols(&self) -> u32;
     fn screen_rows(&self) -> u32;
@@ -113,12 +312,39 @@ pub trait Screen {
     fn screen_restore_cursor(&mut self);
     fn screen_cursor(&mut self, x: u32, y: u32);
 
+    // Where the next character will be written. Writing doesn't move the
+    // hardware cursor, so pass this to screen_cursor() to show it there.
+    fn screen_position(&self) -> (u32, u32);
+
     fn screen_save_attrib(&mut self);
     fn screen_restore_attrib(&mut self);
     fn screen_attrib(&mut self, fg: colour::Colour, bg: colour::Colour);
 
+    // The current foreground and background.
+    fn screen_colours(&self) -> (colour::Colour, colour::Colour);
+
     fn screen_write_char(&mut self, c: char);
     fn screen_write(&mut self, s: &str);
+
+    // How many lines to keep once they scroll off the top.
+    fn screen_set_scrollback(&mut self, lines: usize);
+
+    // Moves the view back through the scrollback (positive) or towards the
+    // live screen (negative). Output carries on while scrolled back, but only
+    // shows once the view returns to the live screen.
+    fn screen_scroll_view(&mut self, lines: i32);
+    fn screen_view_live(&mut self);
+}
+
+pub trait PhysicalMemory {
+    // Seeds the frame allocator from the machine's memory map. Called first
+    // thing in Kernel::start, as the heap is carved out of these frames.
+    fn init_physical_memory();
+
+    // Addresses here are physical, and always 4 KiB aligned.
+    fn alloc_frame(&self) -> Option<usize>;
+    fn free_frame(&self, addr: usize);
+    fn alloc_contiguous(&self, count: usize) -> Option<usize>;
 }
 
 pub trait Mmio {
@@ -0,0 +1,139 @@
+/*
+ * Copyright (c) 2013 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Unicode to code page 437, the character set of the VGA text mode font.
+// Characters the font doesn't have are shown as something close (the
+// light line for a heavy box-drawing line, the letter without its accent),
+// and failing that as '?'.
+
+// The font's glyphs outside printable ASCII, sorted by character. The ones
+// below 0x20 are only reached this way - as characters, those bytes are
+// controls.
+static GLYPHS: [(char, u8); 160] = [
+    ('\u{a0}', 0xFF), ('¡', 0xAD), ('¢', 0x9B), ('£', 0x9C), ('¥', 0x9D),
+    ('§', 0x15), ('ª', 0xA6), ('«', 0xAE), ('¬', 0xAA), ('°', 0xF8),
+    ('±', 0xF1), ('²', 0xFD), ('µ', 0xE6), ('¶', 0x14), ('·', 0xFA),
+    ('º', 0xA7), ('»', 0xAF), ('¼', 0xAC), ('½', 0xAB), ('¿', 0xA8),
+    ('Ä', 0x8E), ('Å', 0x8F), ('Æ', 0x92), ('Ç', 0x80), ('É', 0x90),
+    ('Ñ', 0xA5), ('Ö', 0x99), ('Ü', 0x9A), ('ß', 0xE1), ('à', 0x85),
+    ('á', 0xA0), ('â', 0x83), ('ä', 0x84), ('å', 0x86), ('æ', 0x91),
+    ('ç', 0x87), ('è', 0x8A), ('é', 0x82), ('ê', 0x88), ('ë', 0x89),
+    ('ì', 0x8D), ('í', 0xA1), ('î', 0x8C), ('ï', 0x8B), ('ñ', 0xA4),
+    ('ò', 0x95), ('ó', 0xA2), ('ô', 0x93), ('ö', 0x94), ('÷', 0xF6),
+    ('ù', 0x97), ('ú', 0xA3), ('û', 0x96), ('ü', 0x81), ('ÿ', 0x98),
+    ('ƒ', 0x9F), ('Γ', 0xE2), ('Θ', 0xE9), ('Σ', 0xE4), ('Φ', 0xE8),
+    ('Ω', 0xEA), ('α', 0xE0), ('δ', 0xEB), ('ε', 0xEE), ('π', 0xE3),
+    ('σ', 0xE5), ('τ', 0xE7), ('φ', 0xED), ('•', 0x07), ('‼', 0x13),
+    ('ⁿ', 0xFC), ('₧', 0x9E), ('←', 0x1B), ('↑', 0x18), ('→', 0x1A),
+    ('↓', 0x19), ('↔', 0x1D), ('↕', 0x12), ('↨', 0x17), ('∙', 0xF9),
+    ('√', 0xFB), ('∞', 0xEC), ('∟', 0x1C), ('∩', 0xEF), ('≈', 0xF7),
+    ('≡', 0xF0), ('≤', 0xF3), ('≥', 0xF2), ('⌂', 0x7F), ('⌐', 0xA9),
+    ('⌠', 0xF4), ('⌡', 0xF5), ('─', 0xC4), ('│', 0xB3), ('┌', 0xDA),
+    ('┐', 0xBF), ('└', 0xC0), ('┘', 0xD9), ('├', 0xC3), ('┤', 0xB4),
+    ('┬', 0xC2), ('┴', 0xC1), ('┼', 0xC5), ('═', 0xCD), ('║', 0xBA),
+    ('╒', 0xD5), ('╓', 0xD6), ('╔', 0xC9), ('╕', 0xB8), ('╖', 0xB7),
+    ('╗', 0xBB), ('╘', 0xD4), ('╙', 0xD3), ('╚', 0xC8), ('╛', 0xBE),
+    ('╜', 0xBD), ('╝', 0xBC), ('╞', 0xC6), ('╟', 0xC7), ('╠', 0xCC),
+    ('╡', 0xB5), ('╢', 0xB6), ('╣', 0xB9), ('╤', 0xD1), ('╥', 0xD2),
+    ('╦', 0xCB), ('╧', 0xCF), ('╨', 0xD0), ('╩', 0xCA), ('╪', 0xD8),
+    ('╫', 0xD7), ('╬', 0xCE), ('▀', 0xDF), ('▄', 0xDC), ('█', 0xDB),
+    ('▌', 0xDD), ('▐', 0xDE), ('░', 0xB0), ('▒', 0xB1), ('▓', 0xB2),
+    ('■', 0xFE), ('▬', 0x16), ('▲', 0x1E), ('►', 0x10), ('▼', 0x1F),
+    ('◄', 0x11), ('○', 0x09), ('◘', 0x08), ('◙', 0x0A), ('☺', 0x01),
+    ('☻', 0x02), ('☼', 0x0F), ('♀', 0x0C), ('♂', 0x0B), ('♠', 0x06),
+    ('♣', 0x05), ('♥', 0x03), ('♦', 0x04), ('♪', 0x0D), ('♫', 0x0E),
+];
+
+// Stand-ins for characters the font doesn't have, sorted by character.
+static FALLBACKS: [(char, u8); 319] = [
+    ('¤', b'*'), ('¦', b'|'), ('¨', b'"'), ('©', b'C'), ('®', b'R'),
+    ('¯', b'-'), ('³', b'3'), ('´', b'\''), ('¸', b','), ('¹', b'1'),
+    ('À', b'A'), ('Á', b'A'), ('Â', b'A'), ('Ã', b'A'), ('È', b'E'),
+    ('Ê', b'E'), ('Ë', b'E'), ('Ì', b'I'), ('Í', b'I'), ('Î', b'I'),
+    ('Ï', b'I'), ('Ð', b'D'), ('Ò', b'O'), ('Ó', b'O'), ('Ô', b'O'),
+    ('Õ', b'O'), ('×', b'x'), ('Ø', b'O'), ('Ù', b'U'), ('Ú', b'U'),
+    ('Û', b'U'), ('Ý', b'Y'), ('Þ', b'P'), ('ã', b'a'), ('ð', b'd'),
+    ('õ', b'o'), ('ø', b'o'), ('ý', b'y'), ('þ', b'p'), ('Ā', b'A'),
+    ('ā', b'a'), ('Ă', b'A'), ('ă', b'a'), ('Ą', b'A'), ('ą', b'a'),
+    ('Ć', b'C'), ('ć', b'c'), ('Ĉ', b'C'), ('ĉ', b'c'), ('Ċ', b'C'),
+    ('ċ', b'c'), ('Č', b'C'), ('č', b'c'), ('Ď', b'D'), ('ď', b'd'),
+    ('Đ', b'D'), ('đ', b'd'), ('Ē', b'E'), ('ē', b'e'), ('Ĕ', b'E'),
+    ('ĕ', b'e'), ('Ė', b'E'), ('ė', b'e'), ('Ę', b'E'), ('ę', b'e'),
+    ('Ě', b'E'), ('ě', b'e'), ('Ĝ', b'G'), ('ĝ', b'g'), ('Ğ', b'G'),
+    ('ğ', b'g'), ('Ġ', b'G'), ('ġ', b'g'), ('Ģ', b'G'), ('ģ', b'g'),
+    ('Ĥ', b'H'), ('ĥ', b'h'), ('Ĩ', b'I'), ('ĩ', b'i'), ('Ī', b'I'),
+    ('ī', b'i'), ('Ĭ', b'I'), ('ĭ', b'i'), ('Į', b'I'), ('į', b'i'),
+    ('İ', b'I'), ('ı', b'i'), ('Ĵ', b'J'), ('ĵ', b'j'), ('Ķ', b'K'),
+    ('ķ', b'k'), ('ĸ', b'k'), ('Ĺ', b'L'), ('ĺ', b'l'), ('Ļ', b'L'),
+    ('ļ', b'l'), ('Ľ', b'L'), ('ľ', b'l'), ('Ł', b'L'), ('ł', b'l'),
+    ('Ń', b'N'), ('ń', b'n'), ('Ņ', b'N'), ('ņ', b'n'), ('Ň', b'N'),
+    ('ň', b'n'), ('Ŋ', b'N'), ('ŋ', b'n'), ('Ō', b'O'), ('ō', b'o'),
+    ('Ŏ', b'O'), ('ŏ', b'o'), ('Ő', b'O'), ('ő', b'o'), ('Œ', b'O'),
+    ('œ', b'o'), ('Ŕ', b'R'), ('ŕ', b'r'), ('Ŗ', b'R'), ('ŗ', b'r'),
+    ('Ř', b'R'), ('ř', b'r'), ('Ś', b'S'), ('ś', b's'), ('Ŝ', b'S'),
+    ('ŝ', b's'), ('Ş', b'S'), ('ş', b's'), ('Š', b'S'), ('š', b's'),
+    ('Ţ', b'T'), ('ţ', b't'), ('Ť', b'T'), ('ť', b't'), ('Ũ', b'U'),
+    ('ũ', b'u'), ('Ū', b'U'), ('ū', b'u'), ('Ŭ', b'U'), ('ŭ', b'u'),
+    ('Ů', b'U'), ('ů', b'u'), ('Ű', b'U'), ('ű', b'u'), ('Ų', b'U'),
+    ('ų', b'u'), ('Ŵ', b'W'), ('ŵ', b'w'), ('Ŷ', b'Y'), ('ŷ', b'y'),
+    ('Ÿ', b'Y'), ('Ź', b'Z'), ('ź', b'z'), ('Ż', b'Z'), ('ż', b'z'),
+    ('Ž', b'Z'), ('ž', b'z'), ('β', 0xE1), ('μ', 0xE6), ('ϕ', 0xED),
+    ('ϵ', 0xEE), ('\u{2002}', b' '), ('\u{2003}', b' '), ('\u{2009}', b' '),
+    ('‐', b'-'), ('‑', b'-'), ('‒', b'-'), ('–', b'-'), ('—', b'-'),
+    ('―', b'-'), ('‘', b'\''), ('’', b'\''), ('‚', b'\''), ('‛', b'\''),
+    ('“', b'"'), ('”', b'"'), ('„', b'"'), ('‟', b'"'), ('…', b'.'),
+    ('\u{202f}', b' '), ('′', b'\''), ('″', b'"'), ('‹', b'<'), ('›', b'>'),
+    ('€', b'E'), ('™', b'T'), ('Ω', 0xEA), ('∅', 0xED), ('∈', 0xEE),
+    ('∑', 0xE4), ('−', b'-'), ('⋅', 0xF9), ('━', 0xC4), ('┃', 0xB3),
+    ('┄', 0xC4), ('┅', 0xC4), ('┆', 0xB3), ('┇', 0xB3), ('┈', 0xC4),
+    ('┉', 0xC4), ('┊', 0xB3), ('┋', 0xB3), ('┍', 0xDA), ('┎', 0xDA),
+    ('┏', 0xDA), ('┑', 0xBF), ('┒', 0xBF), ('┓', 0xBF), ('┕', 0xC0),
+    ('┖', 0xC0), ('┗', 0xC0), ('┙', 0xD9), ('┚', 0xD9), ('┛', 0xD9),
+    ('┝', 0xC3), ('┞', 0xC3), ('┟', 0xC3), ('┠', 0xC3), ('┡', 0xC3),
+    ('┢', 0xC3), ('┣', 0xC3), ('┥', 0xB4), ('┦', 0xB4), ('┧', 0xB4),
+    ('┨', 0xB4), ('┩', 0xB4), ('┪', 0xB4), ('┫', 0xB4), ('┭', 0xC2),
+    ('┮', 0xC2), ('┯', 0xC2), ('┰', 0xC2), ('┱', 0xC2), ('┲', 0xC2),
+    ('┳', 0xC2), ('┵', 0xC1), ('┶', 0xC1), ('┷', 0xC1), ('┸', 0xC1),
+    ('┹', 0xC1), ('┺', 0xC1), ('┻', 0xC1), ('┽', 0xC5), ('┾', 0xC5),
+    ('┿', 0xC5), ('╀', 0xC5), ('╁', 0xC5), ('╂', 0xC5), ('╃', 0xC5),
+    ('╄', 0xC5), ('╅', 0xC5), ('╆', 0xC5), ('╇', 0xC5), ('╈', 0xC5),
+    ('╉', 0xC5), ('╊', 0xC5), ('╋', 0xC5), ('╌', 0xC4), ('╍', 0xC4),
+    ('╎', 0xB3), ('╏', 0xB3), ('╭', 0xDA), ('╮', 0xBF), ('╯', 0xD9),
+    ('╰', 0xC0), ('╱', b'/'), ('╲', b'\\'), ('╳', b'X'), ('╴', 0xC4),
+    ('╵', 0xB3), ('╶', 0xC4), ('╷', 0xB3), ('╸', 0xC4), ('╹', 0xB3),
+    ('╺', 0xC4), ('╻', 0xB3), ('╼', 0xC4), ('╽', 0xB3), ('╾', 0xC4),
+    ('╿', 0xB3), ('▁', 0xDC), ('▂', 0xDC), ('▃', 0xDC), ('▅', 0xDC),
+    ('▆', 0xDC), ('▇', 0xDB), ('▉', 0xDB), ('▊', 0xDB), ('▋', 0xDD),
+    ('▍', 0xDD), ('▎', 0xDD), ('▏', 0xDD), ('▔', 0xDF), ('▕', 0xDE),
+    ('▪', 0xFE), ('△', 0x1E), ('▴', 0x1E), ('▵', 0x1E), ('▶', 0x10),
+    ('▷', 0x10), ('▸', 0x10), ('▹', 0x10), ('▽', 0x1F), ('▾', 0x1F),
+    ('▿', 0x1F), ('◀', 0x11), ('◁', 0x11), ('◂', 0x11), ('◃', 0x11),
+    ('◆', 0x07), ('●', 0x07), ('◦', 0x09), ('◯', 0x09), ('◼', 0xFE),
+    ('◾', 0xFE), ('✓', 0xFB), ('✔', 0xFB), ('✗', b'x'), ('✘', b'x'),
+];
+
+pub fn from_char(c: char) -> u8 {
+    // ASCII is the same, and the other controls show their glyphs.
+    if c <= '~' {
+        return c as u8;
+    }
+
+    lookup(&GLYPHS, c).or_else(|| lookup(&FALLBACKS, c)).unwrap_or(b'?')
+}
+
+fn lookup(table: &[(char, u8)], c: char) -> Option<u8> {
+    table.binary_search_by_key(&c, |&(k, _)| k).ok().map(|i| table[i].1)
+}
@@ -14,109 +14,363 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
-use crate::mach;
-use crate::mach::{Keyboard, IoPort};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::mach::{Keyboard, IoPort, Screen};
+use crate::mach::keys::{self, KeyEvent};
+use crate::util::sync::channel::{self, Receiver, SyncSender};
 use crate::Kernel;
 
+use super::layouts::{self, Layout, Mapped};
+use super::ps2::{self, Port};
+
 static KEYBOARD_IRQ: usize = 1;
-static KEYBOARD_CMD: u16 = 0x60;
-static KEYBOARD_DATA: u16 = 0x64;
 
-// Scan code set #1
-static SCAN_CODE_MAPPING: &'static str = "\
-\x00\x1B1234567890-=\x08\tqwertyuiop[]\n?asdfghjkl;'`?\\zxcvbnm,./?*? ?????????????789-456+1230.?????";
-static SCAN_CODE_MAPPING_SHIFTED: &'static str = "\
-\x00\x1B!@#$%^&*()_+\x08\tQWERTYUIOP{}\n?ASDFGHJKL:\"~?|ZXCVBNM<>??*? ?????????????789-456+1230.?????";
+// Events beyond this many are dropped until someone reads some.
+static KEY_QUEUE_DEPTH: usize = 64;
+
+// LED bits for kb_leds().
+static LED_SCROLL_LOCK: u8 = 0b1;
+static LED_NUM_LOCK: u8 = 0b10;
+static LED_CAPS_LOCK: u8 = 0b100;
+
+// Internal bits for which modifier keys are held down, so that letting go of
+// one side doesn't cancel the other.
+static HELD_LEFT_SHIFT: u8 = 1 << 0;
+static HELD_RIGHT_SHIFT: u8 = 1 << 1;
+static HELD_LEFT_CTRL: u8 = 1 << 2;
+static HELD_RIGHT_CTRL: u8 = 1 << 3;
+
+// Where we are in a multi-byte scan code sequence.
+#[derive(Copy, Clone)]
+enum Prefix {
+    None,
+    // 0xE0 - the next byte is an extended key.
+    Extended,
+    // 0xE1 - Pause, which is followed by two more bytes.
+    Pause(u8),
+}
 
 pub struct PS2Keyboard {
-    shifted: bool,
+    modifiers: u8,
+    held: u8,
     ledstate: u8,
+    prefix: Prefix,
+    layout: &'static Layout,
+    // Accent from a dead key, waiting for the next key.
+    dead: Option<char>,
+    events: SyncSender<KeyEvent>,
+    receiver: Arc<Receiver<KeyEvent>>,
 }
 
 impl PS2Keyboard {
     pub fn new() -> PS2Keyboard {
-        PS2Keyboard{shifted: false, ledstate: 0u8}
+        let (tx, rx) = channel::sync_channel(KEY_QUEUE_DEPTH);
+        PS2Keyboard{
+            modifiers: 0,
+            held: 0,
+            ledstate: 0u8,
+            prefix: Prefix::None,
+            layout: layouts::layout(keys::Layout::Us),
+            dead: None,
+            events: tx,
+            receiver: Arc::new(rx)
+        }
     }
 
     pub fn irq_num() -> usize {
         KEYBOARD_IRQ
     }
 
-    fn gotkey(&mut self, scancode: usize) {
+    // Feeds in one byte from the keyboard, and turns complete sequences into
+    // events, keeping track of modifiers on the way. Also returns whether the
+    // LEDs need updating.
+    fn gotkey(&mut self, scancode: u8) -> (Option<KeyEvent>, bool) {
+        let pressed = scancode & 0x80 == 0;
+        let code = scancode & !0x80u8;
+
+        let extended = match (self.prefix, scancode) {
+            (Prefix::None, 0xE0) => {
+                self.prefix = Prefix::Extended;
+                return (None, false);
+            },
+            (Prefix::None, 0xE1) => {
+                self.prefix = Prefix::Pause(0);
+                return (None, false);
+            },
+            (Prefix::Pause(0), _) => {
+                self.prefix = Prefix::Pause(1);
+                return (None, false);
+            },
+            (Prefix::Pause(_), _) => {
+                // E1 1D 45 on press, E1 9D C5 on release.
+                self.prefix = Prefix::None;
+                return (Some(self.event(keys::PAUSE, pressed, None)), false);
+            },
+            (Prefix::Extended, _) => {
+                self.prefix = Prefix::None;
+                true
+            },
+            (Prefix::None, _) => false
+        };
+
+        if extended {
+            return (self.extended_key(code, pressed), false);
+        }
+
         // Sanity.
-        if scancode > 0x58 { return; }
+        if code > 0x58 { return (None, false); }
+
+        let mut leds = 0;
+        match (code, pressed) {
+            (0x2A, _) => self.set_held(HELD_LEFT_SHIFT, pressed),
+            (0x36, _) => self.set_held(HELD_RIGHT_SHIFT, pressed),
+            (0x1D, _) => self.set_held(HELD_LEFT_CTRL, pressed),
+            (0x38, _) => self.set_modifier(keys::ALT, pressed),
+            (0x3A, true) => leds = LED_CAPS_LOCK,
+            (0x45, true) => leds = LED_NUM_LOCK,
+            (0x46, true) => leds = LED_SCROLL_LOCK,
+            _ => {}
+        }
+
+        // Lock keys take effect on this event.
+        self.toggle_locks(leds);
 
-        let _ = match self.shifted {
-            true => SCAN_CODE_MAPPING_SHIFTED,
-            false => SCAN_CODE_MAPPING
-        }.chars().nth(scancode).unwrap();
+        let ch = if pressed { self.translate(code) } else { None };
+        (Some(self.event(code as u16, pressed, ch)), leds != 0)
+    }
+
+    fn extended_key(&mut self, code: u8, pressed: bool) -> Option<KeyEvent> {
+        let ch = match code {
+            // Fake shifts sent around some keys (e.g. Print Screen) so that
+            // they look unshifted to old software.
+            0x2A | 0x36 => return None,
+            0x1D => {
+                self.set_held(HELD_RIGHT_CTRL, pressed);
+                None
+            },
+            0x38 => {
+                self.set_modifier(keys::ALT_GR, pressed);
+                None
+            },
+            0x1C if pressed => Some('\n'),
+            0x35 if pressed => Some('/'),
+            _ => None
+        };
+
+        Some(self.event(0xE000 | code as u16, pressed, ch))
+    }
 
-        // TODO: write the key into a queue that can be read out of!
+    fn event(&self, keycode: u16, pressed: bool, ch: Option<char>) -> KeyEvent {
+        KeyEvent{keycode: keycode, pressed: pressed, modifiers: self.modifiers, ch: ch}
     }
 
-    fn kbcmdwait(&self, kernel: &Kernel) {
-        loop {
-            let status: u8 = kernel.inport(KEYBOARD_DATA);
-            if status & 0x2 == 0 { break; }
+    fn toggle_locks(&mut self, leds: u8) {
+        self.ledstate ^= leds;
+        self.modifiers ^= led_modifiers(leds);
+    }
+
+    fn set_held(&mut self, key: u8, on: bool) {
+        if on {
+            self.held |= key;
+        } else {
+            self.held &= !key;
         }
+
+        let shift = self.held & (HELD_LEFT_SHIFT | HELD_RIGHT_SHIFT) != 0;
+        let ctrl = self.held & (HELD_LEFT_CTRL | HELD_RIGHT_CTRL) != 0;
+        self.set_modifier(keys::SHIFT, shift);
+        self.set_modifier(keys::CTRL, ctrl);
     }
 
-    fn kbdatawait(&self, kernel: &Kernel) {
-        loop {
-            let status: u8 = kernel.inport(KEYBOARD_DATA);
-            if status & 0x1 != 0 { break; }
+    fn set_modifier(&mut self, modifier: u8, on: bool) {
+        if on {
+            self.modifiers |= modifier;
+        } else {
+            self.modifiers &= !modifier;
         }
     }
+
+    fn translate(&mut self, code: u8) -> Option<char> {
+        let shifted = self.modifiers & keys::SHIFT != 0;
+        let altgr = self.modifiers & keys::ALT_GR != 0;
+
+        // The keypad is the same in every layout, and its digits only type
+        // with num lock on.
+        if code >= 0x47 && code <= 0x53 {
+            if code != 0x4A && code != 0x4E && self.modifiers & keys::NUM_LOCK == 0 {
+                return None;
+            }
+
+            return match self.layout.map(code, false, false) {
+                Mapped::Char(c) => Some(c),
+                _ => None
+            };
+        }
+
+        let c = match self.layout.map(code, shifted, altgr) {
+            Mapped::Nothing => return None,
+            Mapped::Dead(accent) => {
+                // Pressing a dead key twice types the accent itself.
+                if self.dead.take() == Some(accent) {
+                    return Some(accent);
+                }
+
+                self.dead = Some(accent);
+                return None;
+            },
+            Mapped::Char(c) => c
+        };
+
+        if c.is_ascii_alphabetic() && self.modifiers & keys::CTRL != 0 {
+            self.dead = None;
+            return Some(((c as u8) & 0x1F) as char);
+        }
+
+        let c = if self.modifiers & keys::CAPS_LOCK != 0 { swap_case(c) } else { c };
+
+        // A dead key followed by something it can't accent just gives the
+        // key, except for space which gives the accent.
+        match self.dead.take() {
+            Some(accent) if c == ' ' => Some(accent),
+            Some(accent) => Some(layouts::compose(accent, c).unwrap_or(c)),
+            None => Some(c)
+        }
+    }
+}
+
+// The keyboard won't take the LED state until it has acknowledged the
+// command. Called with the kernel locked, so the IRQ can't take the
+// acknowledgements first.
+fn write_leds(kernel: &Kernel) {
+    if ps2::device_command(kernel, Port::First, 0xED) {
+        ps2::device_command(kernel, Port::First, kernel.mach.state.keyboard.ledstate);
+    }
+}
+
+// Caps lock inverts the case of letters, whether or not shift is held.
+fn swap_case(c: char) -> char {
+    if c.is_lowercase() && c.to_uppercase().count() == 1 {
+        c.to_uppercase().next().unwrap()
+    } else if c.is_uppercase() && c.to_lowercase().count() == 1 {
+        c.to_lowercase().next().unwrap()
+    } else {
+        c
+    }
+}
+
+fn led_modifiers(leds: u8) -> u8 {
+    let mut modifiers = 0;
+    if leds & LED_CAPS_LOCK != 0 { modifiers |= keys::CAPS_LOCK; }
+    if leds & LED_NUM_LOCK != 0 { modifiers |= keys::NUM_LOCK; }
+    if leds & LED_SCROLL_LOCK != 0 { modifiers |= keys::SCROLL_LOCK; }
+    modifiers
 }
 
 impl Keyboard for Kernel {
-    fn kb_init(&mut self) {
-        // Put the keyboard into scan code set 1, ready for our mapping.
-        self.mach.state.keyboard.kbcmdwait(self);
-        self.outport(0x60, 0xF0u8);
-        self.mach.state.keyboard.kbcmdwait(self);
-        self.outport(0x60, 1u8);
+    fn kb_init(&mut self) -> bool {
+        if !self.mach.state.ps2.port_ok(Port::First) {
+            return false;
+        }
+
+        // Put the keyboard into scan code set 2, which the controller
+        // translates into set 1, ready for our mapping. Not every keyboard
+        // lets us choose, but set 2 is the default anyway.
+        let _ = ps2::device_command(self, Port::First, 0xF0) && ps2::device_command(self, Port::First, 2);
+
+        // Start sending keys.
+        ps2::device_command(self, Port::First, 0xF4)
     }
 
+    // Toggles the given LEDs, along with the lock state they show.
     fn kb_leds(&mut self, state: u8) {
-        self.mach.state.keyboard.ledstate ^= state;
-        self.mach.state.keyboard.kbcmdwait(self);
-        self.outport(KEYBOARD_CMD, 0xEDu8);
-        self.mach.state.keyboard.kbcmdwait(self);
-        self.outport(KEYBOARD_CMD, self.mach.state.keyboard.ledstate);
+        self.mach.state.keyboard.toggle_locks(state);
+        write_leds(self);
+    }
+
+    fn kb_layout(&mut self, layout: keys::Layout) {
+        let kb = &mut self.mach.state.keyboard;
+        kb.layout = layouts::layout(layout);
+        kb.dead = None;
+    }
+
+    fn read_key() -> KeyEvent {
+        let receiver = Arc::clone(&Kernel::kernel().lock().unwrap().mach.state.keyboard.receiver);
+
+        // We hold one end of the channel ourselves, so it never disconnects.
+        receiver.recv().unwrap()
+    }
+
+    fn poll_key() -> Option<KeyEvent> {
+        let receiver = Arc::clone(&Kernel::kernel().lock().unwrap().mach.state.keyboard.receiver);
+        receiver.try_recv().ok()
     }
 }
 
-impl mach::IrqHandler for PS2Keyboard {
-    fn irq(&self, _: usize) {
-        // TODO: figure out the mutable/immutable thing
+// Shift+PgUp/PgDn page the console's scrollback. Returns which way to go
+// (releases don't go anywhere, but are still ours).
+fn scrollback_key(event: &KeyEvent) -> Option<i32> {
+    if event.modifiers & keys::SHIFT == 0 {
+        return None;
+    }
+
+    match (event.keycode, event.pressed) {
+        (keys::PAGE_UP, true) => Some(1),
+        (keys::PAGE_DOWN, true) => Some(-1),
+        (keys::PAGE_UP, false) | (keys::PAGE_DOWN, false) => Some(0),
+        _ => None,
+    }
+}
+
+pub fn kb_irq(_: usize) {
+    let locked_kernel = Kernel::kernel();
+    let mut kernel = locked_kernel.lock().unwrap();
+
+    // Drain everything the controller has for us.
+    let mut events = Vec::new();
+    loop {
+        // Leave anything from the mouse for its own IRQ.
+        let status: u8 = kernel.inport(ps2::PS2_STATUS);
+        if status & (ps2::STATUS_OUTPUT_FULL | ps2::STATUS_AUX_DATA) != ps2::STATUS_OUTPUT_FULL {
+            break;
+        }
 
-        /*
-        // Check status, make sure a key is actually pending.
-        let status: u8 = self.inport(KEYBOARD_DATA);
-        if status & 0x1 == 0 {
-            return;
+        let scancode: u8 = kernel.inport(ps2::PS2_DATA);
+        match scancode {
+            // Responses to our commands (e.g. setting LEDs), not keys.
+            0xFA | 0xFE | 0xEE | 0x00 | 0xFF => continue,
+            _ => {}
         }
 
-        // Get scancode.
-        let scancode: u8 = self.inport(KEYBOARD_CMD);
-
-        // Top bit set means 'key up'
-        if scancode & 0x80 != 0 {
-            let code = scancode & !0x80u8;
-            match code {
-                0x2A | 0x36 => { self.shifted = false },
-                0x3A => self.leds(0b100), // Caps lock
-                0x45 => self.leds(0b10), // Number lock
-                0x46 => self.leds(0b1), // Scroll lock
-                _ => self.gotkey(code as usize)
+        let (event, leds_changed) = kernel.mach.state.keyboard.gotkey(scancode);
+        if leds_changed {
+            write_leds(&kernel);
+        }
+
+        if let Some(event) = event {
+            if let Some(lines) = scrollback_key(&event) {
+                // Paging the console is ours, the key isn't passed on.
+                let rows = kernel.screen_rows() as i32 / 2;
+                kernel.screen_scroll_view(lines * rows);
+                continue;
             }
-        } else {
-            match scancode {
-                0x2A | 0x36 => { self.shifted = true },
-                _ => {}
+
+            // Typing brings the console back to the live screen.
+            if event.pressed && event.ch.is_some() {
+                kernel.screen_view_live();
             }
+
+            events.push(event);
         }
-        */
+    }
+
+    let sender = kernel.mach.state.keyboard.events.clone();
+    drop(kernel);
+
+    // Sending can wake a thread, which needs the kernel lock. If nobody is
+    // reading and the queue is full, the keys are dropped.
+    for event in events {
+        let _ = sender.try_send(event);
     }
 }
@@ -0,0 +1,142 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Keyboard layouts, as tables indexed by scan code set 1 make code. Each
+// table has one character per code up to 0x58, with \0 for keys that don't
+// type anything at that level.
+
+use crate::mach::keys;
+
+pub struct Layout {
+    normal: &'static str,
+    shifted: &'static str,
+    altgr: &'static str,
+    // (level, code) of keys that are dead: they type nothing themselves,
+    // but accent the next key. Level 0 is normal, 1 shifted, 2 AltGr.
+    dead: &'static [(u8, u8)],
+}
+
+pub enum Mapped {
+    Char(char),
+    Dead(char),
+    Nothing,
+}
+
+impl Layout {
+    pub fn map(&self, code: u8, shifted: bool, altgr: bool) -> Mapped {
+        let (level, table) = match (altgr, shifted) {
+            (true, _) => (2, self.altgr),
+            (false, true) => (1, self.shifted),
+            (false, false) => (0, self.normal)
+        };
+
+        match table.chars().nth(code as usize) {
+            None | Some('\0') => Mapped::Nothing,
+            Some(c) if self.dead.contains(&(level, code)) => Mapped::Dead(c),
+            Some(c) => Mapped::Char(c)
+        }
+    }
+}
+
+pub fn layout(which: keys::Layout) -> &'static Layout {
+    match which {
+        keys::Layout::Us => &US,
+        keys::Layout::Uk => &UK,
+        keys::Layout::De => &DE,
+    }
+}
+
+// Combines a dead key's accent with the next character, if there's a
+// precomposed character for the pair.
+pub fn compose(accent: char, c: char) -> Option<char> {
+    let (from, to) = match accent {
+        '´' => ("aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+        '`' => ("aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+        '^' => ("aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+        _ => return None
+    };
+
+    from.chars().position(|f| f == c).and_then(|i| to.chars().nth(i))
+}
+
+// US (ANSI) layout.
+pub static US: Layout = Layout{
+    normal: "\
+\0\x1B1234567890-=\x08\t\
+qwertyuiop[]\n\0\
+asdfghjkl;'`\0\\\
+zxcvbnm,./\0*\0 \
+\0\0\0\0\0\0\0\0\0\0\0\0\0789-456+1230.\0\0\\\0\0",
+    shifted: "\
+\0\x1B!@#$%^&*()_+\x08\t\
+QWERTYUIOP{}\n\0\
+ASDFGHJKL:\"~\0|\
+ZXCVBNM<>?\0*\0 \
+\0\0\0\0\0\0\0\0\0\0\0\0\0789-456+1230.\0\0|\0\0",
+    altgr: "\
+\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\
+\0\0\0\0\0\0\0\0\0\0\0\0\0\0\
+\0\0\0\0\0\0\0\0\0\0\0\0\0\0\
+\0\0\0\0\0\0\0\0\0\0\0\0\0\0\
+\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
+    dead: &[],
+};
+
+// UK layout. AltGr gives the euro sign and acute vowels.
+pub static UK: Layout = Layout{
+    normal: "\
+\0\x1B1234567890-=\x08\t\
+qwertyuiop[]\n\0\
+asdfghjkl;'`\0#\
+zxcvbnm,./\0*\0 \
+\0\0\0\0\0\0\0\0\0\0\0\0\0789-456+1230.\0\0\\\0\0",
+    shifted: "\
+\0\x1B!\"£$%^&*()_+\x08\t\
+QWERTYUIOP{}\n\0\
+ASDFGHJKL:@¬\0~\
+ZXCVBNM<>?\0*\0 \
+\0\0\0\0\0\0\0\0\0\0\0\0\0789-456+1230.\0\0|\0\0",
+    altgr: "\
+\0\0\0\0\0€\0\0\0\0\0\0\0\0\0\0\
+\0\0é\0\0\0úíó\0\0\0\0\0\
+á\0\0\0\0\0\0\0\0\0\0¦\0\0\
+\0\0\0\0\0\0\0\0\0\0\0\0\0\0\
+\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0",
+    dead: &[],
+};
+
+// German (QWERTZ) layout, with dead acute, grave and circumflex.
+pub static DE: Layout = Layout{
+    normal: "\
+\0\x1B1234567890ß´\x08\t\
+qwertzuiopü+\n\0\
+asdfghjklöä^\0#\
+yxcvbnm,.-\0*\0 \
+\0\0\0\0\0\0\0\0\0\0\0\0\0789-456+1230.\0\0<\0\0",
+    shifted: "\
+\0\x1B!\"§$%&/()=?`\x08\t\
+QWERTZUIOPÜ*\n\0\
+ASDFGHJKLÖÄ°\0'\
+YXCVBNM;:_\0*\0 \
+\0\0\0\0\0\0\0\0\0\0\0\0\0789-456+1230.\0\0>\0\0",
+    altgr: "\
+\0\0\0²³\0\0\0{[]}\\\0\0\0\
+@\0€\0\0\0\0\0\0\0\0~\0\0\
+\0\0\0\0\0\0\0\0\0\0\0\0\0\0\
+\0\0\0\0\0\0µ\0\0\0\0\0\0\0\
+\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0|\0\0",
+    dead: &[(0, 0xd), (1, 0xd), (0, 0x29)],
+};
@@ -18,24 +18,35 @@ use alloc::sync::Arc;
 use alloc::boxed::Box;
 use core::default::Default;
 
-use crate::mach::{IrqController, IrqRegister, IrqHandler, HardwareTimer, Machine, TimerHandlers, Keyboard, IoPort, Serial, Mmio};
+use crate::mach::{IrqController, IrqRegister, IrqHandler, HardwareTimer, Machine, TimerHandlers, Keyboard, Mouse, IoPort, Serial, Mmio};
 use crate::mach::parity::Parity;
 use crate::util::sync::Spinlock;
 
 use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 
 use crate::Kernel;
 
+mod cp437;
 mod kb;
+mod layouts;
+mod mouse;
+mod multiboot;
 mod pic;
 mod pit;
+mod ps2;
 mod serial;
 mod vga;
 
+pub use self::serial::SerialPort;
+
 pub struct State {
     irq_ctlr: pic::Pic,
     timer: pit::Pit,
     keyboard: kb::PS2Keyboard,
+    mouse: mouse::PS2Mouse,
+    ps2: ps2::Controller,
+    serial: [Spinlock<serial::Uart>; 4],
     screen: vga::Vga,
     timer_handlers: VecDeque<extern "Rust" fn(&mut Kernel, usize)>,
 }
@@ -45,6 +56,12 @@ impl State {
         State{irq_ctlr: pic::Pic::new(),
               timer: pit::Pit::new(),
               keyboard: kb::PS2Keyboard::new(),
+              mouse: mouse::PS2Mouse::new(),
+              ps2: ps2::Controller::new(),
+              serial: [Spinlock::new(serial::Uart::new(0)),
+                       Spinlock::new(serial::Uart::new(1)),
+                       Spinlock::new(serial::Uart::new(2)),
+                       Spinlock::new(serial::Uart::new(3))],
               screen: vga::Vga::new(),
               timer_handlers: VecDeque::with_capacity(16)}
     }
@@ -66,22 +83,31 @@ fn pc_inport<T: Default>(port: u16) -> T {
 
 impl Machine for Kernel {
     fn mach_initialise(&mut self) -> bool {
-        // Configure serial port.
-        self.serial_config(115200, 8, Parity::NoParity, 1);
-
         // Bring up the PIC.
         self.init_irqs();
 
+        // Find the serial ports, and configure the debug port. Output on
+        // them is buffered from here on.
+        serial::init(self);
+        self.serial_config(115200, 8, Parity::NoParity, 1);
+
         // Bring up the PIT at 100hz.
         self.init_timers(100);
 
-        // Bring up the keyboard.
-        self.kb_init();
+        // Bring up the PS/2 controller, and the keyboard and mouse on it.
+        let ps2 = ps2::init(self);
+        let keyboard = ps2 && self.kb_init();
+        let mouse = ps2 && self.mouse_init();
 
-        // Register the PIT and keyboard IRQs.
-        // TODO: fix these borrows
+        // Register the PIT, keyboard and mouse IRQs.
+        self.register_irq_immediate(pit::Pit::irq_num(), pit::timer_tick);
         self.register_irq(pit::Pit::irq_num(), pit::timer_irq, true);
-        //self.register_irq(kb::PS2Keyboard::irq_num(), &self.mach.state.keyboard, true);
+        if keyboard {
+            self.register_irq(kb::PS2Keyboard::irq_num(), kb::kb_irq, true);
+        }
+        if mouse {
+            self.register_irq(mouse::PS2Mouse::irq_num(), mouse::mouse_irq, true);
+        }
 
         // Set up the VGA screen.
         self.mach.state.screen.init();
@@ -92,10 +118,17 @@ impl Machine for Kernel {
     }
 
     fn debug(msg: &str) {
-        // Blast the debug message straight on the serial port.
-        for b in msg.bytes() {
-            pc_outport(0x3F8 as u16, b);
-        }
+        serial::debug_write(msg);
+    }
+
+    fn reboot(&self) -> ! {
+        // Pulse the reset line through the keyboard controller, and failing
+        // that, ask the chipset.
+        ps2::reset_cpu(self);
+        self.outport(0xCF9, 0x02u8);
+        self.outport(0xCF9, 0x06u8);
+
+        panic!("unable to reboot");
     }
 }
 
@@ -104,6 +137,10 @@ impl TimerHandlers for Kernel {
         self.mach.state.timer_handlers.push_back(f);
     }
 
+    fn timers(&self) -> Vec<extern "Rust" fn(&mut Kernel, usize)> {
+        self.mach.state.timer_handlers.iter().cloned().collect()
+    }
+
     fn timer_fired(&mut self, ms: usize) {
         for h in self.mach.state.timer_handlers.clone().iter() {
             let handler = *h;
@@ -122,6 +159,8 @@ impl IoPort for Kernel {
     }
 }
 
+// Addresses must be mapped (see VirtualMemory) - low memory and RAM are
+// identity mapped during boot, but other device memory is not.
 impl Mmio for Kernel {
     fn mmio_write<T>(&self, address: u32, val: T) {
         let ptr = address as *mut T;
@@ -0,0 +1,174 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// PS/2 mouse on the 8042's auxiliary port.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::mach::{IoPort, Mouse};
+use crate::mach::mouse::{self, MouseEvent};
+use crate::util::sync::channel::{self, Receiver, SyncSender};
+use crate::Kernel;
+
+use super::ps2::{self, Port};
+
+static MOUSE_IRQ: usize = 12;
+
+static MOUSE_QUEUE_DEPTH: usize = 64;
+
+pub struct PS2Mouse {
+    // 4 with an IntelliMouse wheel, 3 otherwise.
+    packet_size: usize,
+    packet: [u8; 4],
+    received: usize,
+    buttons: u8,
+    events: SyncSender<MouseEvent>,
+    receiver: Arc<Receiver<MouseEvent>>,
+}
+
+impl PS2Mouse {
+    pub fn new() -> PS2Mouse {
+        let (tx, rx) = channel::sync_channel(MOUSE_QUEUE_DEPTH);
+        PS2Mouse{
+            packet_size: 3,
+            packet: [0; 4],
+            received: 0,
+            buttons: 0,
+            events: tx,
+            receiver: Arc::new(rx)
+        }
+    }
+
+    pub fn irq_num() -> usize {
+        MOUSE_IRQ
+    }
+
+    // Collects packet bytes, returning an event once a packet is complete.
+    fn gotbyte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // Bit 3 of the first byte is always set. If it isn't, we've lost
+        // track of where packets start - drop bytes until we find one.
+        if self.received == 0 && byte & 0x8 == 0 {
+            return None;
+        }
+
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet_size {
+            return None;
+        }
+
+        self.received = 0;
+
+        let flags = self.packet[0];
+        let (mut dx, mut dy) = (0, 0);
+
+        // Movement is meaningless if it overflowed.
+        if flags & 0xC0 == 0 {
+            dx = (self.packet[1] as i32) - (((flags as i32) << 4) & 0x100);
+            dy = (self.packet[2] as i32) - (((flags as i32) << 3) & 0x100);
+        }
+
+        let wheel = match self.packet_size {
+            4 => (self.packet[3] as i8) as i32,
+            _ => 0
+        };
+
+        let buttons = flags & (mouse::LEFT | mouse::RIGHT | mouse::MIDDLE);
+        let changed = buttons ^ self.buttons;
+        self.buttons = buttons;
+
+        // The mouse reports up as positive, the screen counts rows down.
+        Some(MouseEvent{dx: dx, dy: -dy, wheel: wheel, buttons: buttons, changed: changed})
+    }
+}
+
+fn mouse_write(kernel: &Kernel, byte: u8) -> bool {
+    ps2::device_command(kernel, Port::Second, byte)
+}
+
+fn set_sample_rate(kernel: &Kernel, rate: u8) -> bool {
+    mouse_write(kernel, 0xF3) && mouse_write(kernel, rate)
+}
+
+impl Mouse for Kernel {
+    fn mouse_init(&mut self) -> bool {
+        if !self.mach.state.ps2.port_ok(Port::Second) {
+            return false;
+        }
+
+        // Defaults, so we know what we're dealing with.
+        if !mouse_write(self, 0xF6) {
+            return false;
+        }
+
+        // This magic sequence of sample rates switches an IntelliMouse into
+        // wheel mode, which it then reports in its ID.
+        let mut packet_size = 3;
+        if set_sample_rate(self, 200) && set_sample_rate(self, 100) && set_sample_rate(self, 80) && mouse_write(self, 0xF2) {
+            if ps2::read_data(self) == Some(3) {
+                packet_size = 4;
+            }
+        }
+
+        // Start sending packets.
+        if !mouse_write(self, 0xF4) {
+            return false;
+        }
+
+        self.mach.state.mouse.packet_size = packet_size;
+        true
+    }
+
+    fn read_mouse() -> MouseEvent {
+        let receiver = Arc::clone(&Kernel::kernel().lock().unwrap().mach.state.mouse.receiver);
+
+        // We hold one end of the channel ourselves, so it never disconnects.
+        receiver.recv().unwrap()
+    }
+
+    fn poll_mouse() -> Option<MouseEvent> {
+        let receiver = Arc::clone(&Kernel::kernel().lock().unwrap().mach.state.mouse.receiver);
+        receiver.try_recv().ok()
+    }
+}
+
+pub fn mouse_irq(_: usize) {
+    let locked_kernel = Kernel::kernel();
+    let mut kernel = locked_kernel.lock().unwrap();
+
+    // Drain everything the controller has for us (but leave keyboard bytes).
+    let mut events = Vec::new();
+    loop {
+        let status: u8 = kernel.inport(ps2::PS2_STATUS);
+        let aux = ps2::STATUS_OUTPUT_FULL | ps2::STATUS_AUX_DATA;
+        if status & aux != aux {
+            break;
+        }
+
+        let byte: u8 = kernel.inport(ps2::PS2_DATA);
+        if let Some(event) = kernel.mach.state.mouse.gotbyte(byte) {
+            events.push(event);
+        }
+    }
+
+    let sender = kernel.mach.state.mouse.events.clone();
+    drop(kernel);
+
+    for event in events {
+        let _ = sender.try_send(event);
+    }
+}
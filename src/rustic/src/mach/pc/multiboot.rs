@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use core::mem::size_of;
+
+use crate::Kernel;
+use crate::mach::PhysicalMemory;
+use crate::util::mem;
+
+use simplealloc::frames::FrameBitmap;
+
+static MULTIBOOT_MAGIC: u32 = 0x2BADB002;
+
+// Flags in the multiboot information structure.
+static FLAG_MEMINFO: u32 = 1 << 0;
+static FLAG_CMDLINE: u32 = 1 << 2;
+static FLAG_MODS: u32 = 1 << 3;
+static FLAG_MMAP: u32 = 1 << 6;
+
+// Memory map entry type for usable RAM. Everything else is reserved.
+static MMAP_AVAILABLE: u32 = 1;
+
+// Stashed by _start before calling main.
+extern {
+    static multiboot_magic: u32;
+    static multiboot_info: u32;
+}
+
+// End of the kernel image, from the linker script (not actually a function).
+extern { fn _end(); }
+
+#[repr(C, packed)]
+struct MultibootInfo {
+    flags: u32,
+    mem_lower: u32,
+    mem_upper: u32,
+    boot_device: u32,
+    cmdline: u32,
+    mods_count: u32,
+    mods_addr: u32,
+    syms: [u32; 4],
+    mmap_length: u32,
+    mmap_addr: u32,
+}
+
+#[repr(C, packed)]
+struct MultibootModule {
+    mod_start: u32,
+    mod_end: u32,
+    string: u32,
+    reserved: u32,
+}
+
+#[repr(C, packed)]
+struct MultibootMmapEntry {
+    size: u32,
+    base_addr: u64,
+    length: u64,
+    entry_type: u32,
+}
+
+impl PhysicalMemory for Kernel {
+    fn init_physical_memory() {
+        mem::init_frames(init_frames);
+    }
+
+    fn alloc_frame(&self) -> Option<usize> {
+        mem::alloc_frame()
+    }
+
+    fn free_frame(&self, addr: usize) {
+        mem::free_frame(addr)
+    }
+
+    fn alloc_contiguous(&self, count: usize) -> Option<usize> {
+        mem::alloc_contiguous(count)
+    }
+}
+
+fn init_frames(frames: &mut FrameBitmap) {
+    let (magic, info_addr) = unsafe { (multiboot_magic, multiboot_info) };
+    if magic != MULTIBOOT_MAGIC {
+        panic!("not booted by a multiboot loader (magic {:#x})", magic);
+    }
+
+    let info = unsafe { &*(info_addr as *const MultibootInfo) };
+    let flags = info.flags;
+
+    if (flags & FLAG_MMAP) != 0 {
+        // Add all the usable RAM first, and then knock out everything else,
+        // as some firmware reports reserved ranges overlapping usable ones.
+        for_each_mmap_entry(info, |base, length, entry_type| {
+            if entry_type == MMAP_AVAILABLE {
+                frames.add_region(base, length);
+            }
+        });
+
+        for_each_mmap_entry(info, |base, length, entry_type| {
+            if entry_type != MMAP_AVAILABLE {
+                frames.reserve_region(base, length);
+            }
+        });
+
+        frames.reserve_region(info.mmap_addr as u64, info.mmap_length as u64);
+    } else if (flags & FLAG_MEMINFO) != 0 {
+        // mem_upper is the number of KiB of memory starting at 1 MiB.
+        frames.add_region(0x100000, (info.mem_upper as u64) * 1024);
+    } else {
+        panic!("multiboot loader did not provide a memory map");
+    }
+
+    // Everything from the BIOS data area, through the VGA framebuffer and up
+    // to the end of the kernel image.
+    frames.reserve_region(0, _end as usize as u64);
+
+    // Keep the multiboot structures around in case we want them again.
+    frames.reserve_region(info_addr as u64, size_of::<MultibootInfo>() as u64);
+
+    if (flags & FLAG_CMDLINE) != 0 {
+        reserve_string(frames, info.cmdline);
+    }
+
+    if (flags & FLAG_MODS) != 0 {
+        let count = info.mods_count as usize;
+        let mods = info.mods_addr as *const MultibootModule;
+        frames.reserve_region(mods as u64, (count * size_of::<MultibootModule>()) as u64);
+
+        for i in 0..count {
+            let module = unsafe { &*mods.add(i) };
+            let start = module.mod_start as u64;
+            let end = module.mod_end as u64;
+            // A bootloader could report a module ending before it starts.
+            frames.reserve_region(start, end.saturating_sub(start));
+            reserve_string(frames, module.string);
+        }
+    }
+}
+
+fn for_each_mmap_entry<F: FnMut(u64, u64, u32)>(info: &MultibootInfo, mut f: F) {
+    let mut addr = info.mmap_addr as usize;
+    let end = addr + info.mmap_length as usize;
+
+    while addr < end {
+        let entry = unsafe { &*(addr as *const MultibootMmapEntry) };
+        f(entry.base_addr, entry.length, entry.entry_type);
+
+        // The size field does not include itself.
+        addr += entry.size as usize + size_of::<u32>();
+    }
+}
+
+fn reserve_string(frames: &mut FrameBitmap, addr: u32) {
+    if addr == 0 {
+        return;
+    }
+
+    let s = addr as *const u8;
+    let mut len = 0;
+    while unsafe { *s.add(len) } != 0 {
+        len += 1;
+    }
+
+    frames.reserve_region(addr as u64, (len + 1) as u64);
+}
@@ -18,19 +18,24 @@ use core::sync::atomic;
 use core::sync::atomic::Ordering;
 use alloc::sync::Arc;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 use crate::util::sync::Spinlock;
 
-use crate::arch::{Architecture, TrapHandler, ThreadSpawn, Threads};
+use crate::arch::{Architecture, TrapFrame, TrapHandler, ThreadSpawn, Threads};
 
-use crate::mach::{IoPort, IrqController, IrqHandler, IrqRegister, Machine, Serial};
+use crate::mach::{IoPort, IrqController, IrqHandler, IrqRegister, Machine};
+use crate::mach::irq::IrqInfo;
+use crate::util::logging::warn;
 
 use crate::Kernel;
 
 #[derive(Copy, Clone)]
 struct PicIrqHandler {
-    f: extern "Rust" fn(usize),
+    f: Option<extern "Rust" fn(usize)>,
     level: bool,
+    // Runs straight from the interrupt, rather than on the IRQ thread.
+    immediate: Option<extern "Rust" fn(&mut Kernel, usize)>,
 }
 
 pub static REMAP_BASE: usize = 0x20;
@@ -40,6 +45,12 @@ pub struct Pic {
     active_irqs: atomic::AtomicUsize
 }
 
+impl PicIrqHandler {
+    fn new() -> PicIrqHandler {
+        PicIrqHandler{f: None, level: false, immediate: None}
+    }
+}
+
 impl Pic {
     pub fn new() -> Pic {
         Pic{
@@ -82,26 +93,28 @@ impl IrqController for Kernel {
                                 continue;
                             }
 
-                            // Call handlers - and run the EOI as well.
+                            // Call the handler - and run the EOI as well.
                             // We have to do this here now that we're actually actioning the IRQ.
-                            for h in handlers.iter() {
-                                match h {
-                                    Some (handler) => {
-                                        if handler.level == false {
-                                            kernel_locked.lock().unwrap().eoi(irqnum);
-                                        }
-
-                                        (handler.f)(irqnum);
-
-                                        if handler.level == true {
-                                            kernel_locked.lock().unwrap().eoi(irqnum);
-                                        }
-
-                                        // Unmask the IRQ now that we've handled it.
-                                        kernel_locked.lock().unwrap().enable_irq(irqnum);
-                                    },
-                                    None => {}
-                                }
+                            match handlers[irqnum] {
+                                Some(PicIrqHandler{f: Some(f), immediate: Some(_), ..}) => {
+                                    // Already acknowledged, and never masked.
+                                    f(irqnum);
+                                },
+                                Some(PicIrqHandler{f: Some(f), level, ..}) => {
+                                    if level == false {
+                                        kernel_locked.lock().unwrap().eoi(irqnum);
+                                    }
+
+                                    f(irqnum);
+
+                                    if level == true {
+                                        kernel_locked.lock().unwrap().eoi(irqnum);
+                                    }
+
+                                    // Unmask the IRQ now that we've handled it.
+                                    kernel_locked.lock().unwrap().enable_irq(irqnum);
+                                },
+                                _ => {}
                             }
                         }
 
@@ -120,7 +133,11 @@ impl IrqController for Kernel {
             let actual = irq - 8;
             let curr: u8 = self.inport(0xA1);
             let flag: u8 = 1 << actual;
-            self.outport(0xA1, curr & !flag)
+            self.outport(0xA1, curr & !flag);
+
+            // Slave IRQs come through the cascade on IRQ 2.
+            let curr: u8 = self.inport(0x21);
+            self.outport(0x21, curr & !(1u8 << 2))
         } else {
             let curr: u8 = self.inport(0x21);
             let flag: u8 = 1 << irq;
@@ -149,18 +166,39 @@ impl IrqController for Kernel {
 
 impl IrqRegister for Kernel {
     fn register_irq(&mut self, irq: usize, handler: extern "Rust" fn(usize), level_trigger: bool) {
-        let irqhandler = PicIrqHandler{f: handler, level: level_trigger};
+        let mut irqhandler = self.mach.state.irq_ctlr.irqhandlers[irq].unwrap_or(PicIrqHandler::new());
+        irqhandler.f = Some(handler);
+        irqhandler.level = level_trigger;
+        self.mach.state.irq_ctlr.irqhandlers[irq] = Some(irqhandler);
+
+        self.register_trap(irq + REMAP_BASE, irq_stub);
+        self.enable_irq(irq);
+    }
+
+    fn register_irq_immediate(&mut self, irq: usize, handler: extern "Rust" fn(&mut Kernel, usize)) {
+        let mut irqhandler = self.mach.state.irq_ctlr.irqhandlers[irq].unwrap_or(PicIrqHandler::new());
+        irqhandler.immediate = Some(handler);
         self.mach.state.irq_ctlr.irqhandlers[irq] = Some(irqhandler);
 
         self.register_trap(irq + REMAP_BASE, irq_stub);
         self.enable_irq(irq);
     }
+
+    fn irq_handlers(&self) -> Vec<IrqInfo> {
+        let handlers = self.mach.state.irq_ctlr.irqhandlers.iter().enumerate();
+        handlers.filter_map(|(irq, handler)| {
+            handler.map(|h| IrqInfo{
+                irq: irq,
+                deferred: h.f.is_some(),
+                immediate: h.immediate.is_some(),
+                level_trigger: h.level,
+            })
+        }).collect()
+    }
 }
 
 impl TrapHandler for Kernel {
     fn trap(&mut self, num: usize) -> Option<extern "Rust" fn(usize)> {
-        let irq_ctlr = &self.mach.state.irq_ctlr;
-
         let irqnum = num - REMAP_BASE;
 
         // Get status registers for master/slave
@@ -173,27 +211,37 @@ impl TrapHandler for Kernel {
         // Spurious IRQ?
         if irqnum == 7 {
             if (isr & (1 << 7)) == 0 {
-                self.serial_write("spurious IRQ 7\n");
+                warn!("spurious IRQ 7");
                 return None;
             }
         } else if irqnum == 15 {
             if (isr & (1 << 15)) == 0 {
-                self.serial_write("spurious IRQ 15\n");
+                warn!("spurious IRQ 15");
                 self.eoi(7);
                 return None;
             }
         }
 
         if (isr & (1 << irqnum)) == 0 {
-            self.serial_write("IRQ stub called with no interrupt status\n");
+            warn!("IRQ {} stub called with no interrupt status", irqnum);
             return None;
         }
 
-        // Get the handler we need.
-        // TODO: mark as active and let a thread handle the code so we don't
-        // spend forever in the IRQ handler running code
+        let irq_ctlr = &self.mach.state.irq_ctlr;
         match irq_ctlr.irqhandlers[irqnum] {
+            Some(PicIrqHandler{immediate: Some(handler), f, ..}) => {
+                // Handled right here, so the IRQ is never masked. Any deferred
+                // handler runs later on the IRQ thread.
+                if f.is_some() {
+                    irq_ctlr.active_irqs.fetch_or(1 << irqnum, Ordering::SeqCst);
+                }
+
+                handler(self, irqnum);
+                self.eoi(irqnum);
+            },
             Some(_) => {
+                // Mark the IRQ as active and let the IRQ thread run the
+                // handler, so we don't spend forever in the interrupt handler.
                 irq_ctlr.active_irqs.fetch_or(1 << irqnum, Ordering::SeqCst);
 
                 // Mask IRQ until we're done handling it.
@@ -201,7 +249,7 @@ impl TrapHandler for Kernel {
             },
             None => {
                 // Unhandled IRQ, just send the EOI and hope all's well.
-                self.serial_write("Unhandled IRQ");
+                warn!("unhandled IRQ {}", irqnum);
                 self.eoi(irqnum);
             }
         };
@@ -210,6 +258,6 @@ impl TrapHandler for Kernel {
     }
 }
 
-fn irq_stub(which: usize) {
-    Kernel::kernel().lock().unwrap().trap(which);
+fn irq_stub(frame: &mut TrapFrame) {
+    Kernel::kernel().lock().unwrap().trap(frame.vector as usize);
 }
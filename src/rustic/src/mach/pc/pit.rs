@@ -14,22 +14,28 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
+use core::sync::atomic::{AtomicUsize, Ordering};
 use alloc::sync::Arc;
 use alloc::boxed::Box;
 
 use crate::Kernel;
+use crate::arch::Threads;
 use crate::mach::{IrqHandler, HardwareTimer, TimerHandlers, IoPort};
 use crate::util::sync::Spinlock;
 
 static BASE_FREQUENCY: usize = 1193180;
 
+// Ticks are counted straight from the interrupt, so this is atomic.
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
 pub struct Pit {
     timer_hz: usize,
+    handled_ticks: usize,
 }
 
 impl Pit {
     pub fn new() -> Pit{
-        Pit{timer_hz: 0}
+        Pit{timer_hz: 0, handled_ticks: 0}
     }
 
     pub fn irq_num() -> usize {
@@ -48,6 +54,10 @@ impl HardwareTimer for Kernel {
         self.outport(0x40, (div & 0xFF) as u8);
         self.outport(0x40, ((div >> 8) & 0xFF) as u8);
     }
+
+    fn timer_hz(&self) -> usize {
+        self.mach.state.timer.timer_hz
+    }
 }
 
 impl IrqHandler for Pit {
@@ -57,9 +67,26 @@ impl IrqHandler for Pit {
     }
 }
 
+// Runs straight from the interrupt on every tick, rather than being deferred
+// to the IRQ thread: the scheduler needs to see time passing even if a busy
+// thread is keeping the IRQ thread from running.
+pub fn timer_tick(kernel: &mut Kernel, _: usize) {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+
+    let hz = kernel.mach.state.timer.timer_hz;
+    kernel.scheduler_tick(1000 / hz);
+}
+
 pub fn timer_irq(_: usize) {
     let locked_kernel = Kernel::kernel();
     let mut kernel = locked_kernel.lock().unwrap();
     let hz = kernel.mach.state.timer.timer_hz;
-    kernel.timer_fired(1000 / hz);
+
+    // Several ticks may have gone by since the IRQ thread last ran. Fire
+    // the handlers once for each so they see every tick.
+    let ticks = TICKS.load(Ordering::SeqCst);
+    while kernel.mach.state.timer.handled_ticks != ticks {
+        kernel.mach.state.timer.handled_ticks = kernel.mach.state.timer.handled_ticks.wrapping_add(1);
+        kernel.timer_fired(1000 / hz);
+    }
 }
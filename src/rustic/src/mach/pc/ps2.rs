@@ -0,0 +1,289 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The 8042 PS/2 controller, which the keyboard and mouse drivers talk to
+// their devices through. Everything here gives up after a while rather than
+// waiting forever on hardware that isn't there.
+
+use crate::mach::IoPort;
+use crate::Kernel;
+use crate::util::logging::warn;
+
+pub static PS2_DATA: u16 = 0x60;
+pub static PS2_STATUS: u16 = 0x64;
+static PS2_COMMAND: u16 = 0x64;
+
+pub static STATUS_OUTPUT_FULL: u8 = 0x1;
+static STATUS_INPUT_FULL: u8 = 0x2;
+// Set along with STATUS_OUTPUT_FULL when the byte is from the second port.
+pub static STATUS_AUX_DATA: u8 = 0x20;
+
+static CONFIG_PORT1_IRQ: u8 = 0x1;
+static CONFIG_PORT2_IRQ: u8 = 0x2;
+static CONFIG_PORT1_CLOCK_OFF: u8 = 0x10;
+static CONFIG_PORT2_CLOCK_OFF: u8 = 0x20;
+static CONFIG_TRANSLATE: u8 = 0x40;
+
+pub static DEVICE_ACK: u8 = 0xFA;
+static DEVICE_RESEND: u8 = 0xFE;
+
+// How many times to poll the controller before giving up on it.
+static PS2_TIMEOUT: usize = 100000;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Port {
+    // The keyboard port.
+    First = 0,
+    // The auxiliary (mouse) port.
+    Second = 1,
+}
+
+pub struct Controller {
+    present: bool,
+    ports: [bool; 2],
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller{present: false, ports: [false, false]}
+    }
+
+    // Whether the port passed its tests and has a device on it that
+    // answered a reset.
+    pub fn port_ok(&self, port: Port) -> bool {
+        self.present && self.ports[port as usize]
+    }
+}
+
+fn wait_input_clear(kernel: &Kernel) -> bool {
+    for _ in 0..PS2_TIMEOUT {
+        let status: u8 = kernel.inport(PS2_STATUS);
+        if status & STATUS_INPUT_FULL == 0 {
+            return true;
+        }
+    }
+
+    false
+}
+
+pub fn read_data(kernel: &Kernel) -> Option<u8> {
+    for _ in 0..PS2_TIMEOUT {
+        let status: u8 = kernel.inport(PS2_STATUS);
+        if status & STATUS_OUTPUT_FULL != 0 {
+            return Some(kernel.inport(PS2_DATA));
+        }
+    }
+
+    None
+}
+
+fn command(kernel: &Kernel, command: u8) -> bool {
+    if !wait_input_clear(kernel) {
+        return false;
+    }
+
+    kernel.outport(PS2_COMMAND, command);
+    true
+}
+
+fn command_with_data(kernel: &Kernel, cmd: u8, data: u8) -> bool {
+    if !command(kernel, cmd) || !wait_input_clear(kernel) {
+        return false;
+    }
+
+    kernel.outport(PS2_DATA, data);
+    true
+}
+
+fn command_with_response(kernel: &Kernel, cmd: u8) -> Option<u8> {
+    if !command(kernel, cmd) {
+        return None;
+    }
+
+    read_data(kernel)
+}
+
+// Pulses the CPU reset line. Returns if the controller isn't there.
+pub fn reset_cpu(kernel: &Kernel) {
+    if command(kernel, 0xFE) {
+        // Give it a moment to take effect.
+        for _ in 0..PS2_TIMEOUT {
+            let _: u8 = kernel.inport(PS2_STATUS);
+        }
+    }
+}
+
+// Sends a byte to a device, without waiting for a response. Anything it
+// sends back will arrive through the port's IRQ.
+pub fn device_write(kernel: &Kernel, port: Port, byte: u8) -> bool {
+    if port == Port::Second && !command(kernel, 0xD4) {
+        return false;
+    }
+
+    if !wait_input_clear(kernel) {
+        return false;
+    }
+
+    kernel.outport(PS2_DATA, byte);
+    true
+}
+
+// Sends a byte to a device and waits for it to be acknowledged, resending
+// if the device asks. Only for use while the port's IRQ is not handled, or
+// with the kernel locked so that it can't run.
+pub fn device_command(kernel: &Kernel, port: Port, byte: u8) -> bool {
+    for _ in 0..3 {
+        if !device_write(kernel, port, byte) {
+            return false;
+        }
+
+        match read_data(kernel) {
+            Some(b) if b == DEVICE_ACK => return true,
+            Some(b) if b == DEVICE_RESEND => continue,
+            _ => return false
+        }
+    }
+
+    false
+}
+
+fn read_config(kernel: &Kernel) -> Option<u8> {
+    command_with_response(kernel, 0x20)
+}
+
+fn write_config(kernel: &Kernel, config: u8) -> bool {
+    command_with_data(kernel, 0x60, config)
+}
+
+// Resets the device on a port. Devices take a while to run their self test,
+// so this waits for longer than usual.
+fn reset_device(kernel: &Kernel, port: Port) -> bool {
+    if !device_command(kernel, port, 0xFF) {
+        return false;
+    }
+
+    let mut passed = false;
+    for _ in 0..10 {
+        match read_data(kernel) {
+            Some(0xAA) => {
+                passed = true;
+                break;
+            },
+            Some(_) => return false,
+            None => continue
+        }
+    }
+
+    // Acked the reset but never finished its self test.
+    if !passed {
+        return false;
+    }
+
+    // Mice follow up with their ID.
+    if port == Port::Second {
+        let _ = read_data(kernel);
+    }
+
+    true
+}
+
+// Finds and tests the controller, and the devices on both ports. Leaves
+// working ports enabled, with their IRQs on.
+pub fn init(kernel: &mut Kernel) -> bool {
+    kernel.mach.state.ps2 = Controller::new();
+
+    // No controller at all reads back as all ones.
+    let status: u8 = kernel.inport(PS2_STATUS);
+    if status == 0xFF {
+        warn!("no controller");
+        return false;
+    }
+
+    // Disable both ports so they can't get in the way, then throw away
+    // anything they'd already sent.
+    if !command(kernel, 0xAD) || !command(kernel, 0xA7) {
+        warn!("controller not responding");
+        return false;
+    }
+
+    for _ in 0..16 {
+        let status: u8 = kernel.inport(PS2_STATUS);
+        if status & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+
+        let _: u8 = kernel.inport(PS2_DATA);
+    }
+
+    // IRQs off while we test. Translation stays on, so the keyboard's scan
+    // code set 2 arrives as set 1.
+    let config = match read_config(kernel) {
+        Some(config) => (config & !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ)) | CONFIG_TRANSLATE,
+        None => return false
+    };
+    let maybe_dual = config & CONFIG_PORT2_CLOCK_OFF != 0;
+    write_config(kernel, config);
+
+    if command_with_response(kernel, 0xAA) != Some(0x55) {
+        warn!("controller self test failed");
+        return false;
+    }
+
+    // The self test can reset the configuration.
+    write_config(kernel, config);
+
+    // If the second port's clock turns on when we enable it, it's there.
+    let mut dual = false;
+    if maybe_dual {
+        command(kernel, 0xA8);
+        dual = match read_config(kernel) {
+            Some(c) => c & CONFIG_PORT2_CLOCK_OFF == 0,
+            None => false
+        };
+        command(kernel, 0xA7);
+    }
+
+    let first = command_with_response(kernel, 0xAB) == Some(0);
+    let second = dual && command_with_response(kernel, 0xA9) == Some(0);
+    if !first {
+        warn!("first port failed its test");
+    }
+    if dual && !second {
+        warn!("second port failed its test");
+    }
+
+    kernel.mach.state.ps2.present = true;
+
+    let mut config = config;
+    if first {
+        command(kernel, 0xAE);
+        if reset_device(kernel, Port::First) {
+            kernel.mach.state.ps2.ports[0] = true;
+            config = (config | CONFIG_PORT1_IRQ) & !CONFIG_PORT1_CLOCK_OFF;
+        }
+    }
+
+    if second {
+        command(kernel, 0xA8);
+        if reset_device(kernel, Port::Second) {
+            kernel.mach.state.ps2.ports[1] = true;
+            config = (config | CONFIG_PORT2_IRQ) & !CONFIG_PORT2_CLOCK_OFF;
+        }
+    }
+
+    write_config(kernel, config);
+    true
+}
@@ -14,10 +14,18 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use crate::Kernel;
 
-use crate::mach::{IoPort, Serial};
+use crate::arch::{Debugger, Threads};
+use crate::mach::{IoPort, IrqRegister, Serial, SerialPorts};
+use crate::mach::flow::FlowControl;
 use crate::mach::parity::Parity;
+use crate::mach::serial::{self, SerialErrors};
+use crate::util::sync::Spinlock;
 
 enum Registers {
     RxTx = 0,
@@ -30,20 +38,395 @@ enum Registers {
     Scratch = 7,
 }
 
-static SERIAL_BASE: u16 = 0x3F8;
+// Base I/O port and IRQ of COM1 through COM4.
+static PORTS: [(u16, usize); 4] = [(0x3F8, 4), (0x2F8, 3), (0x3E8, 4), (0x2E8, 3)];
+static NUM_PORTS: usize = 4;
+
+// Which port Machine::debug (and the Serial trait) uses.
+static DEBUG_PORT: AtomicUsize = AtomicUsize::new(0);
+
+// The divisor is relative to this.
+static BASE_BAUD: i32 = 115200;
+
+// How far off (in percent) the rate we can actually run at may be from the
+// one asked for. Much more and the other end won't keep up.
+static MAX_BAUD_ERROR: i32 = 3;
+
+static RX_BUFFER_SIZE: usize = 1024;
+static TX_BUFFER_SIZE: usize = 1024;
+
+// With flow control on, we ask the other end to stop sending once the
+// receive buffer is this full, and to start again once it has drained.
+static RX_HIGH_WATER: usize = RX_BUFFER_SIZE * 3 / 4;
+static RX_LOW_WATER: usize = RX_BUFFER_SIZE / 4;
+
+// Sent by GDB to stop the system, if the port is being used by the stub.
+static GDB_INTERRUPT: u8 = 0x03;
+
+static XON: u8 = 0x11;
+static XOFF: u8 = 0x13;
+
+// How long a polled write waits for CTS before giving up on the byte.
+static CTS_TIMEOUT: usize = 1000000;
+
+// Bytes the transmit FIFO takes each time it empties.
+static TX_FIFO_SIZE: usize = 16;
+
+static INTEN_RX: u8 = 0x1;
+static INTEN_TX: u8 = 0x2;
+static INTEN_LINE_STATUS: u8 = 0x4;
+static INTEN_MODEM_STATUS: u8 = 0x8;
+
+// DTR and RTS, plus OUT2 which connects the UART's interrupt to the PIC.
+static MCTRL_DTR: u8 = 0x1;
+static MCTRL_RTS: u8 = 0x2;
+static MCTRL_OUT2: u8 = 0x8;
+
+static LSTAT_DATA_READY: u8 = 0x1;
+static LSTAT_OVERRUN: u8 = 0x2;
+static LSTAT_PARITY: u8 = 0x4;
+static LSTAT_FRAMING: u8 = 0x8;
+static LSTAT_BREAK: u8 = 0x10;
+static LSTAT_TX_EMPTY: u8 = 0x20;
+
+pub struct Uart {
+    base: u16,
+    irq: usize,
+    present: bool,
+    // Ports that aren't interrupt driven (yet, or because they share an IRQ
+    // with a port that is) are polled.
+    irq_driven: bool,
+    // Whether the transmit interrupt is on, i.e. tx is being drained.
+    transmitting: bool,
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    // Threads waiting in serial_read_from() and serial_wait_modem().
+    readers: VecDeque<usize>,
+    modem_waiters: VecDeque<usize>,
+    errors: SerialErrors,
+
+    flow: FlowControl,
+    // The other end has told us to stop sending (by dropping CTS or with
+    // XOFF).
+    tx_stopped: bool,
+    // We've told the other end to stop sending.
+    rx_throttled: bool,
+    // DTR and RTS as set by set_dtr() and set_rts().
+    mctrl: u8,
+    // Modem status lines, as of the last time we looked.
+    modem_status: u8,
+}
+
+// A handle to one of the serial ports, from SerialPorts::serial().
+pub struct SerialPort<'a> {
+    kernel: &'a Kernel,
+    uart: &'a Spinlock<Uart>,
+}
+
+impl Uart {
+    pub fn new(port: usize) -> Uart {
+        let (base, irq) = PORTS[port];
+        Uart{
+            base: base,
+            irq: irq,
+            present: false,
+            irq_driven: false,
+            transmitting: false,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            readers: VecDeque::new(),
+            modem_waiters: VecDeque::new(),
+            errors: SerialErrors::default(),
+            flow: FlowControl::NoFlowControl,
+            tx_stopped: false,
+            rx_throttled: false,
+            mctrl: MCTRL_DTR | MCTRL_RTS,
+            modem_status: 0,
+        }
+    }
+
+    fn reg(&self, reg: Registers) -> u16 {
+        self.base + reg as u16
+    }
+
+    // A UART has a scratch register that holds whatever we write to it. If
+    // nothing's there, reads come back as all ones.
+    fn probe(&mut self, io: &Kernel) -> bool {
+        self.present = [0x5Au8, 0xA5u8].iter().all(|&test| {
+            io.outport(self.reg(Registers::Scratch), test);
+            let back: u8 = io.inport(self.reg(Registers::Scratch));
+            back == test
+        });
+
+        if self.present {
+            self.rx.reserve(RX_BUFFER_SIZE);
+            self.tx.reserve(TX_BUFFER_SIZE);
+        }
+
+        self.present
+    }
+
+    fn set_interrupts(&self, io: &Kernel) {
+        let mut inten = 0;
+        let mut mctrl = self.mctrl;
+        if self.flow == FlowControl::RtsCts && self.rx_throttled {
+            mctrl &= !MCTRL_RTS;
+        }
+
+        if self.irq_driven {
+            inten = INTEN_RX | INTEN_LINE_STATUS | INTEN_MODEM_STATUS;
+            if self.transmitting {
+                inten |= INTEN_TX;
+            }
+
+            mctrl |= MCTRL_OUT2;
+        }
+
+        io.outport(self.reg(Registers::Inten), inten);
+        io.outport(self.reg(Registers::MCtrl), mctrl);
+    }
+
+    fn line_status(&mut self, io: &Kernel) -> u8 {
+        let status: u8 = io.inport(self.reg(Registers::LStat));
+        if status & LSTAT_OVERRUN != 0 { self.errors.overrun += 1; }
+        if status & LSTAT_PARITY != 0 { self.errors.parity += 1; }
+        if status & LSTAT_FRAMING != 0 { self.errors.framing += 1; }
+        if status & LSTAT_BREAK != 0 { self.errors.breaks += 1; }
+        status
+    }
+
+    // Reads the modem status lines, waking anyone waiting for them to
+    // change if they have. Reading clears the UART's modem status
+    // interrupt, so the IRQ can't be relied on to see every change.
+    // Returns whether CTS is up.
+    fn read_modem_status(&mut self, io: &Kernel) -> bool {
+        let status: u8 = io.inport(self.reg(Registers::MStat));
+        let status = status & serial::MODEM_LINES;
+        if status != self.modem_status {
+            self.modem_status = status;
+            for id in self.modem_waiters.drain(..) {
+                io.thread_wake_deferred(id);
+            }
+        }
+        self.modem_status & serial::CTS != 0
+    }
+
+    // Moves everything in the receive FIFO into the buffer. Returns whether
+    // anything arrived.
+    fn receive(&mut self, io: &Kernel) -> bool {
+        let mut received = false;
+        while self.line_status(io) & LSTAT_DATA_READY != 0 {
+            let byte: u8 = io.inport(self.reg(Registers::RxTx));
+
+            if self.flow == FlowControl::XonXoff && (byte == XON || byte == XOFF) {
+                self.tx_stopped = byte == XOFF;
+                if !self.tx_stopped {
+                    self.start_transmit(io);
+                }
+                continue;
+            }
+
+            if self.rx.len() < RX_BUFFER_SIZE {
+                self.rx.push_back(byte);
+            } else {
+                self.errors.dropped += 1;
+            }
+            received = true;
+        }
+
+        if self.rx.len() >= RX_HIGH_WATER {
+            self.throttle(io, true);
+        }
+
+        received
+    }
+
+    fn pop_rx(&mut self, io: &Kernel) -> Option<u8> {
+        let byte = self.rx.pop_front();
+        if self.rx.len() <= RX_LOW_WATER {
+            self.throttle(io, false);
+        }
+
+        byte
+    }
+
+    // Tells the other end to stop or start sending, as appropriate for the
+    // flow control in use.
+    fn throttle(&mut self, io: &Kernel, stop: bool) {
+        if self.rx_throttled == stop {
+            return;
+        }
+
+        match self.flow {
+            FlowControl::NoFlowControl => return,
+            FlowControl::RtsCts => {
+                self.rx_throttled = stop;
+                self.set_interrupts(io);
+            },
+            FlowControl::XonXoff => {
+                // This jumps the queue, as the other end needs it right now.
+                self.rx_throttled = stop;
+                self.wait_tx_empty(io);
+                io.outport(self.reg(Registers::RxTx), if stop { XOFF } else { XON });
+            }
+        }
+    }
+
+    // Whether the other end is letting us send.
+    fn may_transmit(&mut self, io: &Kernel) -> bool {
+        self.tx_stopped = match self.flow {
+            FlowControl::NoFlowControl => false,
+            FlowControl::RtsCts => !self.read_modem_status(io),
+            FlowControl::XonXoff => self.tx_stopped,
+        };
+
+        !self.tx_stopped
+    }
+
+    fn start_transmit(&mut self, io: &Kernel) {
+        // Turning on the transmit interrupt fires it straight away if the
+        // UART has nothing to send.
+        if self.irq_driven && !self.transmitting && !self.tx.is_empty() {
+            self.transmitting = true;
+            self.set_interrupts(io);
+        }
+    }
+
+    // Refills the (empty) transmit FIFO from the buffer. If the other end
+    // has told us to stop, the transmit interrupt goes off until it says
+    // otherwise.
+    fn transmit(&mut self, io: &Kernel) {
+        if !self.may_transmit(io) {
+            if self.transmitting {
+                self.transmitting = false;
+                self.set_interrupts(io);
+            }
+            return;
+        }
+
+        for _ in 0..TX_FIFO_SIZE {
+            match self.tx.pop_front() {
+                Some(byte) => io.outport(self.reg(Registers::RxTx), byte),
+                None => break
+            }
+        }
+
+        if self.tx.is_empty() && self.transmitting {
+            self.transmitting = false;
+            self.set_interrupts(io);
+        }
+    }
+
+    fn write_byte(&mut self, io: &Kernel, byte: u8) {
+        if !self.irq_driven {
+            let mut waited = 0;
+            while !self.may_transmit(io) {
+                waited += 1;
+                if waited == CTS_TIMEOUT {
+                    self.errors.tx_dropped += 1;
+                    return;
+                }
+            }
+
+            self.wait_tx_empty(io);
+            io.outport(self.reg(Registers::RxTx), byte);
+            return;
+        }
+
+        // Buffer's full - push some out by hand to make room. The IRQ can't
+        // run while we're here, as the kernel is locked - which also means
+        // we can't wait for the other end if it has told us to stop.
+        if self.tx.len() >= TX_BUFFER_SIZE {
+            self.wait_tx_empty(io);
+            self.transmit(io);
+
+            if self.tx.len() >= TX_BUFFER_SIZE {
+                self.errors.tx_dropped += 1;
+                return;
+            }
+        }
+
+        self.tx.push_back(byte);
+        if !self.tx_stopped {
+            self.start_transmit(io);
+        }
+    }
+
+    fn wait_tx_empty(&self, io: &Kernel) {
+        loop {
+            let status: u8 = io.inport(self.reg(Registers::LStat));
+            if (status & LSTAT_TX_EMPTY) != 0 {
+                break;
+            }
+        }
+    }
+
+    fn try_read(&mut self, io: &Kernel) -> Option<u8> {
+        // Polled ports have nothing buffered, so check the UART itself.
+        if !self.irq_driven && self.present {
+            self.receive(io);
+        }
+
+        self.pop_rx(io)
+    }
+
+    // Handles everything the UART is interrupting for.
+    fn service(&mut self, io: &Kernel) -> bool {
+        let mut received = false;
+        loop {
+            let ident: u8 = io.inport(self.reg(Registers::IIFifo));
+            if ident & 0x1 != 0 {
+                break;
+            }
+
+            match (ident >> 1) & 0x7 {
+                0b011 => { self.line_status(io); },
+                0b010 | 0b110 => received |= self.receive(io),
+                0b001 => self.transmit(io),
+                _ => {
+                    // CTS coming back up lets us carry on sending.
+                    if self.read_modem_status(io) && self.flow == FlowControl::RtsCts {
+                        self.start_transmit(io);
+                    }
+                }
+            }
+        }
+
+        received
+    }
+}
+
+impl<'a> SerialPort<'a> {
+    // Returns false if the port isn't there, or the settings aren't
+    // possible. Any standard baud rate from 50 to 115200 works.
+    pub fn config(&self, baud: i32, dbits: i32, parity: Parity, sbits: i32) -> bool {
+        let uart = self.uart.lock().unwrap();
+        if !uart.present || baud <= 0 || baud > BASE_BAUD {
+            return false;
+        }
+
+        // Rounded, as not every rate divides evenly (e.g. 110 baud).
+        let divisor = (BASE_BAUD + (baud / 2)) / baud;
+        if divisor < 1 || divisor > 0xFFFF {
+            return false;
+        }
+
+        // Rates in between the ones the divisor can make (e.g. 80000 baud)
+        // would round to something else entirely.
+        let actual = BASE_BAUD / divisor;
+        if (actual - baud).abs() * 100 > baud * MAX_BAUD_ERROR {
+            return false;
+        }
 
-impl Serial for Kernel {
-    fn serial_config(&self, baud: i32, dbits: i32, parity: Parity, sbits: i32) {
         // Disable IRQs.
-        self.outport(SERIAL_BASE + Registers::Inten as u16, 0 as u8);
+        self.kernel.outport(uart.reg(Registers::Inten), 0 as u8);
 
         // Enable DLAB to set the baud rate divisor.
-        self.outport(SERIAL_BASE + Registers::LCtrl as u16, 0x80 as u8);
+        self.kernel.outport(uart.reg(Registers::LCtrl), 0x80 as u8);
 
         // Set the divisor for the given baud rate.
-        let divisor = 115200 / baud;
-        self.outport(SERIAL_BASE + Registers::RxTx as u16, (divisor & 0xF) as u8);
-        self.outport(SERIAL_BASE + Registers::Inten as u16, ((divisor & 0xF0) >> 8) as u8);
+        self.kernel.outport(uart.reg(Registers::RxTx), (divisor & 0xFF) as u8);
+        self.kernel.outport(uart.reg(Registers::Inten), ((divisor >> 8) & 0xFF) as u8);
 
         // Set data/stop bits and parity, which will also clear DLAB.
         let meta: u8 =
@@ -64,54 +447,344 @@ impl Serial for Kernel {
                 Parity::Space => 0b111000,
                 _             => 0,
             };
-        self.outport(SERIAL_BASE + Registers::LCtrl as u16, meta);
+        self.kernel.outport(uart.reg(Registers::LCtrl), meta);
 
         // Enable and clear the FIFO.
-        self.outport(SERIAL_BASE + Registers::IIFifo as u16, 0xC7 as u8);
+        self.kernel.outport(uart.reg(Registers::IIFifo), 0xC7 as u8);
 
-        // Set RTS/DSR, and enable IRQs for if/when INTEN == 1.
-        self.outport(SERIAL_BASE + Registers::MCtrl as u16, 0x0B as u8);
+        // Set DTR/RTS, and interrupts if we're using them.
+        uart.set_interrupts(self.kernel);
+        true
     }
 
-    fn serial_write(&self, s: &str) {
-        // Pass str as bytes to the serial line (UTF-8 can be read by the other
-        // side, we don't have to do any transformations).
+    pub fn present(&self) -> bool {
+        self.uart.lock().unwrap().present
+    }
+
+    // With RTS/CTS, we only send while CTS is up, and drop RTS when our
+    // receive buffer is getting full. With XON/XOFF, the same is done in-band
+    // and XON/XOFF bytes never show up in what's read.
+    pub fn set_flow_control(&self, flow: FlowControl) {
+        let mut uart = self.uart.lock().unwrap();
+        uart.throttle(self.kernel, false);
+        uart.flow = flow;
+        uart.tx_stopped = false;
+        uart.may_transmit(self.kernel);
+        uart.set_interrupts(self.kernel);
+        uart.start_transmit(self.kernel);
+    }
+
+    // The current state of the modem status lines (see mach::serial).
+    pub fn modem_status(&self) -> u8 {
+        let mut uart = self.uart.lock().unwrap();
+        uart.read_modem_status(self.kernel);
+        uart.modem_status
+    }
+
+    // With RTS/CTS flow control on, RTS is also dropped while our receive
+    // buffer is full, whatever it's set to here.
+    pub fn set_rts(&self, on: bool) {
+        self.set_mctrl(MCTRL_RTS, on);
+    }
+
+    pub fn set_dtr(&self, on: bool) {
+        self.set_mctrl(MCTRL_DTR, on);
+    }
+
+    fn set_mctrl(&self, bit: u8, on: bool) {
+        let mut uart = self.uart.lock().unwrap();
+        if on {
+            uart.mctrl |= bit;
+        } else {
+            uart.mctrl &= !bit;
+        }
+        uart.set_interrupts(self.kernel);
+    }
+
+    // Pass str as bytes to the serial line (UTF-8 can be read by the other
+    // side, we don't have to do any transformations).
+    pub fn write(&self, s: &str) {
         for c in s.chars() {
             if c == '\0' {
                 continue;
             }
-            self.serial_write_char(c);
+            self.write_char(c);
         }
     }
 
-    fn serial_read_char(&self) -> char {
-        // Wait until bytes are pending in the FIFO.
+    pub fn write_char(&self, c: char) {
+        let mut bytes = [0u8; 4];
+        self.write_bytes(c.encode_utf8(&mut bytes).as_bytes());
+    }
+
+    // Writes are buffered, and only wait if the buffer is full.
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        let mut uart = self.uart.lock().unwrap();
+        for &byte in bytes {
+            uart.write_byte(self.kernel, byte);
+        }
+    }
+
+    // Spins until a byte arrives - prefer SerialPorts::serial_read_from().
+    pub fn read_char(&self) -> char {
+        let mut uart = self.uart.lock().unwrap();
+        if let Some(byte) = uart.pop_rx(self.kernel) {
+            return byte as char;
+        }
+
+        // Wait until bytes are pending in the FIFO. The IRQ can't take them
+        // from under us, as the kernel is locked.
         loop {
-            let status: u8 = self.inport(SERIAL_BASE + Registers::LStat as u16);
-            if (status & 0x1) != 0 {
+            let status = uart.line_status(self.kernel);
+            if (status & LSTAT_DATA_READY) != 0 {
                 break;
             }
         }
 
-        let result: u8 = self.inport(SERIAL_BASE + Registers::RxTx as u16);
+        let result: u8 = self.kernel.inport(uart.reg(Registers::RxTx));
         result as char
     }
 
-    fn serial_write_char(&self, c: char) {
-        // Wait until we are permitted to write.
+    pub fn try_read(&self) -> Option<u8> {
+        self.uart.lock().unwrap().try_read(self.kernel)
+    }
+
+    pub fn errors(&self) -> SerialErrors {
+        self.uart.lock().unwrap().errors
+    }
+}
+
+// Finds which ports exist, and switches them over from polling to
+// interrupts. Only one port per IRQ can use it, as they can't share the
+// line.
+pub fn init(kernel: &mut Kernel) {
+    for port in 0..NUM_PORTS {
+        kernel.mach.state.serial[port].lock().unwrap().probe(kernel);
+    }
+
+    let mut irqs = [false; 16];
+    for port in 0..NUM_PORTS {
+        let irq = {
+            let uart = kernel.mach.state.serial[port].lock().unwrap();
+            if !uart.present || irqs[uart.irq] {
+                continue;
+            }
+            uart.irq
+        };
+
+        irqs[irq] = true;
+        kernel.register_irq_immediate(irq, serial_irq);
+
+        let mut uart = kernel.mach.state.serial[port].lock().unwrap();
+        uart.irq_driven = true;
+        uart.set_interrupts(kernel);
+    }
+}
+
+// Runs straight from the interrupt, as the receive FIFO would overflow if
+// we waited for the IRQ thread.
+pub fn serial_irq(kernel: &mut Kernel, irq: usize) {
+    let mut readers = VecDeque::new();
+    for port in 0..NUM_PORTS {
+        let mut uart = kernel.mach.state.serial[port].lock().unwrap();
+        if uart.irq != irq || !uart.irq_driven {
+            continue;
+        }
+
+        if uart.service(kernel) {
+            // While the system is running, all the debugger sends is ^C to
+            // stop it.
+            if kernel.gdb_port() == Some(port) && uart.rx.contains(&GDB_INTERRUPT) {
+                uart.rx.clear();
+                Kernel::gdb_interrupt();
+            }
+
+            readers.append(&mut uart.readers);
+        }
+    }
+
+    for id in readers {
+        kernel.thread_wake(id);
+    }
+}
+
+// Polls the debug port directly, so it works from anywhere - including
+// panics and exceptions.
+pub fn debug_write(msg: &str) {
+    let port = DEBUG_PORT.load(Ordering::SeqCst);
+    for b in msg.bytes() {
+        raw_write(port, b);
+    }
+}
+
+fn raw_write(port: usize, byte: u8) {
+    let base = PORTS[port].0;
+    loop {
+        let status: u8 = super::pc_inport(base + Registers::LStat as u16);
+        if (status & LSTAT_TX_EMPTY) != 0 {
+            break;
+        }
+    }
+
+    super::pc_outport(base, byte);
+}
+
+fn raw_read(port: usize) -> Option<u8> {
+    let base = PORTS[port].0;
+    let status: u8 = super::pc_inport(base + Registers::LStat as u16);
+    if (status & LSTAT_DATA_READY) != 0 {
+        Some(super::pc_inport(base))
+    } else {
+        None
+    }
+}
+
+impl SerialPorts for Kernel {
+    fn serial(&self, port: usize) -> Option<SerialPort<'_>> {
+        if port >= NUM_PORTS {
+            return None;
+        }
+
+        Some(SerialPort{kernel: self, uart: &self.mach.state.serial[port]})
+    }
+
+    fn serial_read_from(port: usize) -> Option<u8> {
+        if port >= NUM_PORTS {
+            return None;
+        }
+
+        let locked_kernel = Kernel::kernel();
+
         loop {
-            let status: u8 = self.inport(SERIAL_BASE + Registers::LStat as u16);
-            if (status & 0x20) != 0 {
-                break;
+            let kernel = locked_kernel.lock().unwrap();
+            let me = kernel.thread_id();
+
+            let mut uart = kernel.mach.state.serial[port].lock().unwrap();
+            if let Some(byte) = uart.try_read(&kernel) {
+                return Some(byte);
+            }
+
+            // Polled ports have no IRQ to wake us, so just keep checking.
+            let irq_driven = uart.irq_driven;
+            if irq_driven && !uart.readers.contains(&me) {
+                uart.readers.push_back(me);
+            }
+
+            drop(uart);
+            drop(kernel);
+
+            if irq_driven {
+                Kernel::thread_block(Arc::clone(&locked_kernel));
+            } else {
+                Kernel::reschedule(Arc::clone(&locked_kernel));
+            }
+        }
+    }
+
+    fn serial_wait_modem(port: usize) -> Option<u8> {
+        if port >= NUM_PORTS {
+            return None;
+        }
+
+        let locked_kernel = Kernel::kernel();
+
+        let kernel = locked_kernel.lock().unwrap();
+        let me = kernel.thread_id();
+        let mut uart = kernel.mach.state.serial[port].lock().unwrap();
+        uart.read_modem_status(&kernel);
+        let before = uart.modem_status;
+        drop(uart);
+        drop(kernel);
+
+        loop {
+            let kernel = locked_kernel.lock().unwrap();
+            let mut uart = kernel.mach.state.serial[port].lock().unwrap();
+
+            // Polled ports don't get modem status interrupts.
+            if !uart.irq_driven {
+                uart.read_modem_status(&kernel);
+            }
+
+            if uart.modem_status != before {
+                return Some(uart.modem_status);
+            }
+
+            let irq_driven = uart.irq_driven;
+            if irq_driven && !uart.modem_waiters.contains(&me) {
+                uart.modem_waiters.push_back(me);
+            }
+
+            drop(uart);
+            drop(kernel);
+
+            if irq_driven {
+                Kernel::thread_block(Arc::clone(&locked_kernel));
+            } else {
+                Kernel::reschedule(Arc::clone(&locked_kernel));
             }
         }
+    }
 
-        // char -> UTF-8 conversion; we must use the length return value rather
-        // than iteration as the number of bytes to write is not static.
-        let mut bytes = [0u8; 6];
-        let encoded = c.encode_utf8(&mut bytes);
-        for index in 0..encoded.len() {
-            self.outport(SERIAL_BASE + Registers::RxTx as u16, bytes[index]);
+    fn serial_raw_write(port: usize, byte: u8) {
+        if port < NUM_PORTS {
+            raw_write(port, byte);
         }
     }
+
+    fn serial_raw_read(port: usize) -> Option<u8> {
+        if port < NUM_PORTS {
+            raw_read(port)
+        } else {
+            None
+        }
+    }
+
+    fn set_debug_port(&mut self, port: usize) -> bool {
+        match self.serial(port) {
+            Some(ref p) if p.present() => {
+                DEBUG_PORT.store(port, Ordering::SeqCst);
+                true
+            },
+            _ => false
+        }
+    }
+
+    fn debug_port(&self) -> usize {
+        DEBUG_PORT.load(Ordering::SeqCst)
+    }
+}
+
+// The debug port.
+impl Serial for Kernel {
+    fn serial_config(&self, baud: i32, dbits: i32, parity: Parity, sbits: i32) {
+        self.serial(self.debug_port()).unwrap().config(baud, dbits, parity, sbits);
+    }
+
+    fn serial_write(&self, s: &str) {
+        self.serial(self.debug_port()).unwrap().write(s);
+    }
+
+    fn serial_read_char(&self) -> char {
+        self.serial(self.debug_port()).unwrap().read_char()
+    }
+
+    fn serial_write_char(&self, c: char) {
+        self.serial(self.debug_port()).unwrap().write_char(c);
+    }
+
+    fn serial_write_bytes(&self, bytes: &[u8]) {
+        self.serial(self.debug_port()).unwrap().write_bytes(bytes);
+    }
+
+    fn serial_try_read(&self) -> Option<u8> {
+        self.serial(self.debug_port()).unwrap().try_read()
+    }
+
+    fn serial_read() -> u8 {
+        Kernel::serial_read_from(DEBUG_PORT.load(Ordering::SeqCst)).unwrap()
+    }
+
+    fn serial_errors(&self) -> SerialErrors {
+        self.serial(self.debug_port()).unwrap().errors()
+    }
 }
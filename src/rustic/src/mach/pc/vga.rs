@@ -14,10 +14,17 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+
 use crate::Kernel;
 
 use crate::mach::{IoPort, Screen, Mmio};
 
+use super::cp437;
+
+use crate::util::ansi::{Parser, Rendition, Sequence};
 use crate::util::colour::Colour;
 
 pub static COLS: u32 = 80;
@@ -25,6 +32,10 @@ pub static ROWS: u32 = 25;
 
 static VGABASE: u32 = 0xB8000;
 
+// Lines kept once they scroll off the top, unless changed with
+// screen_set_scrollback().
+static DEFAULT_SCROLLBACK: usize = 200;
+
 pub struct Vga {
     x: u32,
     y: u32,
@@ -35,6 +46,27 @@ pub struct Vga {
     bg: Colour,
     saved_fg: Colour,
     saved_bg: Colour,
+
+    // Escape sequences in what's written, and the SGR state that isn't in
+    // the colours themselves.
+    ansi: Parser,
+    bold: bool,
+    reversed: bool,
+
+    // Rows that scroll, inclusive.
+    top: u32,
+    bottom: u32,
+
+    // What's on the screen (character and attribute, as in video memory),
+    // which is only a copy of video memory while we're not scrolled back.
+    cells: Vec<u16>,
+
+    // Lines that have scrolled off the top, oldest first.
+    scrollback: VecDeque<Vec<u16>>,
+    scrollback_lines: usize,
+
+    // How many lines back from the live screen we're showing.
+    view: usize,
 }
 
 impl Vga {
@@ -48,28 +80,253 @@ impl Vga {
             bg: Colour::Black,
             saved_fg: Colour::LightGray,
             saved_bg: Colour::Black,
+            ansi: Parser::new(),
+            bold: false,
+            reversed: false,
+            top: 0,
+            bottom: ROWS - 1,
+            cells: vec![0; (COLS * ROWS) as usize],
+            scrollback: VecDeque::new(),
+            scrollback_lines: DEFAULT_SCROLLBACK,
+            view: 0,
         }
     }
 
+    // Picks up whatever the BIOS or bootloader left on the screen.
     pub fn init(&mut self) {
-        // no-op
+        for (i, cell) in self.cells.iter_mut().enumerate() {
+            *cell = unsafe { core::ptr::read_volatile((VGABASE as *const u16).add(i)) };
+        }
+    }
+
+    fn blank(&self) -> u16 {
+        (b' ' as u16) | ((self.bg as u16) << 12) | ((self.fg as u16) << 8)
+    }
+
+    // What belongs on the given row with the view where it is.
+    fn view_row(&self, row: usize) -> &[u16] {
+        let cols = COLS as usize;
+        if row < self.view {
+            &self.scrollback[self.scrollback.len() - self.view + row]
+        } else {
+            let row = row - self.view;
+            &self.cells[row * cols..(row + 1) * cols]
+        }
+    }
+}
+
+impl Kernel {
+    fn vga_put(&mut self, offset: u32, val: u16) {
+        self.mach.state.screen.cells[offset as usize] = val;
+        if self.mach.state.screen.view == 0 {
+            self.mmio_write(VGABASE + (offset * 2), val);
+        }
+    }
+
+    fn vga_redraw(&self) {
+        let screen = &self.mach.state.screen;
+        for row in 0..ROWS as usize {
+            for (col, &val) in screen.view_row(row).iter().enumerate() {
+                self.mmio_write(VGABASE + ((row as u32 * COLS + col as u32) * 2), val);
+            }
+        }
+    }
+
+    fn vga_erase(&mut self, from: u32, to: u32) {
+        let blank = self.mach.state.screen.blank();
+        for offset in from..to {
+            self.vga_put(offset, blank);
+        }
+    }
+
+    // Moves down a line, scrolling if that's off the bottom of the scroll
+    // region.
+    fn vga_index(&mut self) {
+        let screen = &mut self.mach.state.screen;
+        if screen.y == screen.bottom {
+            self.vga_scroll_up(1);
+        } else if screen.y < ROWS - 1 {
+            screen.y += 1;
+        }
+    }
+
+    fn vga_reverse_index(&mut self) {
+        let screen = &mut self.mach.state.screen;
+        if screen.y == screen.top {
+            self.vga_scroll_down(1);
+        } else if screen.y > 0 {
+            screen.y -= 1;
+        }
+    }
+
+    fn vga_newline(&mut self) {
+        self.mach.state.screen.x = 0;
+        self.vga_index();
+    }
+
+    // Moves the scroll region up, keeping what goes off the top for
+    // scrollback if the region is at the top of the screen.
+    fn vga_scroll_up(&mut self, lines: u32) {
+        let screen = &mut self.mach.state.screen;
+        let cols = COLS as usize;
+        let top = screen.top as usize;
+        let bottom = screen.bottom as usize + 1;
+        let lines = (lines as usize).min(bottom - top);
+
+        if top == 0 && screen.scrollback_lines > 0 {
+            for row in 0..lines {
+                if screen.scrollback.len() == screen.scrollback_lines {
+                    screen.scrollback.pop_front();
+                } else if screen.view > 0 {
+                    // Keep showing the same lines.
+                    screen.view += 1;
+                }
+                screen.scrollback.push_back(screen.cells[row * cols..(row + 1) * cols].to_vec());
+            }
+        }
+
+        let blank = screen.blank();
+        screen.cells.copy_within((top + lines) * cols..bottom * cols, top * cols);
+        screen.cells[(bottom - lines) * cols..bottom * cols].iter_mut().for_each(|c| *c = blank);
+
+        if screen.view == 0 {
+            self.vga_redraw();
+        }
+    }
+
+    fn vga_scroll_down(&mut self, lines: u32) {
+        let screen = &mut self.mach.state.screen;
+        let cols = COLS as usize;
+        let top = screen.top as usize;
+        let bottom = screen.bottom as usize + 1;
+        let lines = (lines as usize).min(bottom - top);
+
+        let blank = screen.blank();
+        screen.cells.copy_within(top * cols..(bottom - lines) * cols, (top + lines) * cols);
+        screen.cells[top * cols..(top + lines) * cols].iter_mut().for_each(|c| *c = blank);
+
+        if screen.view == 0 {
+            self.vga_redraw();
+        }
+    }
+
+    // Moves the cursor by the given amount, staying on the screen.
+    fn vga_move(&mut self, dx: i64, dy: i64) {
+        let (x, y) = self.screen_position();
+        let x = (x.min(COLS - 1) as i64 + dx).max(0).min(COLS as i64 - 1);
+        let y = (y as i64 + dy).max(0).min(ROWS as i64 - 1);
+        self.screen_cursor(x as u32, y as u32);
+    }
+
+    fn vga_print(&mut self, c: char) {
+        let attr = ((self.mach.state.screen.bg as u8) << 4) | (self.mach.state.screen.fg as u8);
+
+        match c {
+            // newline
+            '\n' => self.vga_newline(),
+            // carriage return
+            '\r' => {
+                self.mach.state.screen.x = 0;
+            },
+            // tab
+            '\t' => {
+                let x = self.mach.state.screen.x + 4;
+                self.mach.state.screen.x = (x - x % 4).min(COLS);
+            },
+            // backspace
+            '\x08' => {
+                let x = self.mach.state.screen.x.min(COLS - 1);
+                self.mach.state.screen.x = x.saturating_sub(1);
+            },
+            // bell
+            '\x07' => {},
+            '\0' => {},
+            c => {
+                // Wrapping waits until there's something to put on the next
+                // line, so that filling the bottom row doesn't scroll.
+                if self.mach.state.screen.x >= COLS {
+                    self.vga_newline();
+                }
+
+                let offset = (self.mach.state.screen.y * self.screen_cols()) + self.mach.state.screen.x;
+                let val = (cp437::from_char(c) as u16) | ((attr as u16) << 8);
+                self.vga_put(offset, val);
+
+                self.mach.state.screen.x += 1;
+            }
+        };
+    }
+
+    fn vga_sequence(&mut self, seq: Sequence) {
+        let (x, y) = self.screen_position();
+        let position = y * COLS + x.min(COLS - 1);
+
+        match seq {
+            Sequence::Print(c) => self.vga_print(c),
+            Sequence::CursorUp(n) => self.vga_move(0, -(n as i64)),
+            Sequence::CursorDown(n) => self.vga_move(0, n as i64),
+            Sequence::CursorForward(n) => self.vga_move(n as i64, 0),
+            Sequence::CursorBack(n) => self.vga_move(-(n as i64), 0),
+            Sequence::CursorNextLine(n) => self.vga_move(-(x as i64), n as i64),
+            Sequence::CursorPrevLine(n) => self.vga_move(-(x as i64), -(n as i64)),
+            Sequence::CursorColumn(col) => self.vga_move(col as i64 - x as i64, 0),
+            Sequence::CursorRow(row) => self.vga_move(0, row as i64 - y as i64),
+            Sequence::CursorTo{row, col} => self.screen_cursor(col.min(COLS - 1), row.min(ROWS - 1)),
+            Sequence::SaveCursor => self.screen_save_cursor(),
+            Sequence::RestoreCursor => self.screen_restore_cursor(),
+            Sequence::EraseDisplay(0) => self.vga_erase(position, COLS * ROWS),
+            Sequence::EraseDisplay(1) => self.vga_erase(0, position + 1),
+            Sequence::EraseDisplay(_) => self.vga_erase(0, COLS * ROWS),
+            Sequence::EraseLine(0) => self.vga_erase(position, (y + 1) * COLS),
+            Sequence::EraseLine(1) => self.vga_erase(y * COLS, position + 1),
+            Sequence::EraseLine(_) => self.vga_erase(y * COLS, (y + 1) * COLS),
+            Sequence::Graphics(params) => {
+                let screen = &mut self.mach.state.screen;
+                let mut rendition = Rendition{
+                    fg: screen.fg, bg: screen.bg, bold: screen.bold, reversed: screen.reversed};
+                rendition.apply(&params);
+                screen.fg = rendition.fg;
+                screen.bg = rendition.bg;
+                screen.bold = rendition.bold;
+                screen.reversed = rendition.reversed;
+            },
+            Sequence::ScrollUp(n) => self.vga_scroll_up(n),
+            Sequence::ScrollDown(n) => self.vga_scroll_down(n),
+            Sequence::ScrollRegion{top, bottom} => {
+                let bottom = bottom.unwrap_or(ROWS - 1).min(ROWS - 1);
+                if top < bottom {
+                    self.mach.state.screen.top = top;
+                    self.mach.state.screen.bottom = bottom;
+                    self.screen_cursor(0, 0);
+                }
+            },
+            Sequence::Index => self.vga_index(),
+            Sequence::ReverseIndex => self.vga_reverse_index(),
+            Sequence::NextLine => self.vga_newline(),
+            Sequence::Reset => {
+                self.mach.state.screen.top = 0;
+                self.mach.state.screen.bottom = ROWS - 1;
+                self.screen_attrib(Colour::LightGray, Colour::Black);
+                self.screen_clear();
+                self.screen_cursor(0, 0);
+            },
+        }
     }
 }
 
 impl Screen for Kernel {
-    fn screen_clear(&self) {
+    fn screen_clear(&mut self) {
         self.screen_fill(' ');
     }
 
-    fn screen_fill(&self, with: char) {
-        let real_char = safe_char(with);
+    fn screen_fill(&mut self, with: char) {
+        let real_char = cp437::from_char(with);
 
         let field: u16 = (real_char as u16) | ((self.mach.state.screen.bg as u16) << 12);
-        let max = self.screen_rows() * self.screen_cols() * 2;
+        let max = self.screen_rows() * self.screen_cols();
 
-        // TODO: we can do this better - a memset?
-        for offset in (0..max).step_by(2) {
-            self.mmio_write(VGABASE + offset, field);
+        for offset in 0..max {
+            self.vga_put(offset, field);
         }
     }
 
@@ -103,14 +360,23 @@ impl Screen for Kernel {
         self.outport(0x3D4, 0x0Eu8);
         self.outport(0x3D5, ((position >> 8) & 0xFF) as u8);
 
-        let curr: u16 = self.mmio_read(VGABASE + (position * 2));
+        // Past the end of the last row means the next character wraps.
+        if x >= COLS || y >= ROWS {
+            return;
+        }
+
+        let curr = self.mach.state.screen.cells[position as usize];
         let attr: u8 = (curr >> 8) as u8;
         if attr & 0xFu8 == 0 {
             // No foreground colour attribute for cursor location. Fix.
-            self.mmio_write(VGABASE + (position * 2), curr | ((Colour::LightGray as u16) << 8));
+            self.vga_put(position, curr | ((Colour::LightGray as u16) << 8));
         }
     }
 
+    fn screen_position(&self) -> (u32, u32) {
+        (self.mach.state.screen.x, self.mach.state.screen.y)
+    }
+
     fn screen_save_attrib(&mut self) {
         self.mach.state.screen.saved_fg = self.mach.state.screen.fg;
         self.mach.state.screen.saved_bg = self.mach.state.screen.bg;
@@ -124,44 +390,17 @@ impl Screen for Kernel {
     fn screen_attrib(&mut self, fg: Colour, bg: Colour) {
         self.mach.state.screen.fg = fg;
         self.mach.state.screen.bg = bg;
+        self.mach.state.screen.bold = false;
+        self.mach.state.screen.reversed = false;
     }
 
-    fn screen_write_char(&mut self, c: char) {
-        let attr = ((self.mach.state.screen.bg as u8) << 4) | (self.mach.state.screen.fg as u8);
-
-        match safe_char(c) {
-            // newline
-            0x0A => {
-                self.mach.state.screen.x = 0;
-                self.mach.state.screen.y += 1;
-            },
-            // carriage return
-            0x0D => {
-                self.mach.state.screen.x = 0;
-            },
-            // tab
-            0x09 => {
-                self.mach.state.screen.x += 4;
-                self.mach.state.screen.x -= self.mach.state.screen.x % 4;
-            },
-            0x00 => {},
-            glyph => {
-                let offset = (self.mach.state.screen.y * self.screen_cols()) + self.mach.state.screen.x;
-                let val = (glyph as u16) | ((attr as u16) << 8);
-                self.mmio_write(VGABASE + (offset * 2), val);
-
-                self.mach.state.screen.x += 1;
-            }
-        };
-
-        if self.mach.state.screen.x >= self.screen_cols() {
-            self.mach.state.screen.x = 0;
-            self.mach.state.screen.y += 1;
-        }
+    fn screen_colours(&self) -> (Colour, Colour) {
+        (self.mach.state.screen.fg, self.mach.state.screen.bg)
+    }
 
-        // TODO: scroll.
-        if self.mach.state.screen.y >= self.screen_rows() {
-            self.mach.state.screen.y = self.screen_rows() - 1;
+    fn screen_write_char(&mut self, c: char) {
+        if let Some(seq) = self.mach.state.screen.ansi.advance(c) {
+            self.vga_sequence(seq);
         }
     }
 
@@ -174,12 +413,31 @@ impl Screen for Kernel {
             self.screen_write_char(c);
         }
     }
-}
 
-fn safe_char(c: char) -> u8 {
-    if c as u32 > 0xFF {
-        0xDB
-    } else {
-        c as u8
+    fn screen_set_scrollback(&mut self, lines: usize) {
+        let screen = &mut self.mach.state.screen;
+        screen.scrollback_lines = lines;
+        while screen.scrollback.len() > lines {
+            screen.scrollback.pop_front();
+        }
+
+        if screen.view > screen.scrollback.len() {
+            screen.view = screen.scrollback.len();
+            self.vga_redraw();
+        }
+    }
+
+    fn screen_scroll_view(&mut self, lines: i32) {
+        let screen = &mut self.mach.state.screen;
+        let view = (screen.view as i64 + lines as i64).max(0).min(screen.scrollback.len() as i64) as usize;
+        if view != screen.view {
+            screen.view = view;
+            self.vga_redraw();
+        }
+    }
+
+    fn screen_view_live(&mut self) {
+        self.screen_scroll_view(i32::min_value());
     }
 }
+
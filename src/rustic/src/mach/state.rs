@@ -15,7 +15,7 @@
  */
 
 #[cfg(feature="plat_pc")]
-pub use super::pc::State;
+pub use super::pc::{SerialPort, State};
 
 #[cfg(feature="plat_beagle")]
 pub use super::beagle::State;
@@ -0,0 +1,388 @@
+/*
+ * Copyright (c) 2013 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::util::colour::Colour;
+
+// Parameters beyond this many in one sequence are ignored.
+const MAX_PARAMS: usize = 8;
+
+// The parts of VT100 (and a few later additions) a console needs to show the
+// output of programs written for serial terminals. Rows and columns are from
+// zero, and counts are at least one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Sequence {
+    // Anything not part of an escape sequence, including control characters.
+    Print(char),
+
+    CursorUp(u32),
+    CursorDown(u32),
+    CursorForward(u32),
+    CursorBack(u32),
+    // Down or up, to the start of the line.
+    CursorNextLine(u32),
+    CursorPrevLine(u32),
+    CursorColumn(u32),
+    CursorRow(u32),
+    CursorTo{row: u32, col: u32},
+    SaveCursor,
+    RestoreCursor,
+
+    // 0 erases from the cursor to the end, 1 from the start to the cursor and
+    // 2 everything.
+    EraseDisplay(u32),
+    EraseLine(u32),
+
+    // Select Graphic Rendition, to be applied to a Rendition.
+    Graphics(Params),
+
+    // Rows to scroll within the scroll region, and the region itself. A
+    // bottom of None is the last row of the screen.
+    ScrollUp(u32),
+    ScrollDown(u32),
+    ScrollRegion{top: u32, bottom: Option<u32>},
+
+    // Moves down (or up), scrolling at the edge of the scroll region.
+    Index,
+    ReverseIndex,
+    NextLine,
+
+    Reset,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Params {
+    values: [u32; MAX_PARAMS],
+    count: usize,
+}
+
+impl Params {
+    fn new() -> Params {
+        Params{values: [0; MAX_PARAMS], count: 0}
+    }
+
+    // Missing parameters (and explicit zeroes) take the default.
+    pub fn get_or(&self, index: usize, default: u32) -> u32 {
+        match self.values[..self.count].get(index) {
+            Some(&0) | None => default,
+            Some(&v) => v,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.values[..self.count].iter().cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    // ESC followed by an intermediate, e.g. character set selection.
+    EscapeIntermediate,
+    Csi,
+    // A control sequence we don't understand, skipped up to its final byte.
+    CsiIgnore,
+    // Operating system commands (e.g. window titles) are skipped entirely.
+    Osc,
+    OscEscape,
+}
+
+pub struct Parser {
+    state: State,
+    params: Params,
+    // Whether a digit has been seen for the current parameter.
+    digits: bool,
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser{state: State::Ground, params: Params::new(), digits: false}
+    }
+
+    // Feeds one character in. Returns what to do, if the character completes
+    // something.
+    pub fn advance(&mut self, c: char) -> Option<Sequence> {
+        // These abort any sequence, wherever they appear.
+        match c {
+            '\x18' | '\x1A' => {
+                self.state = State::Ground;
+                return None;
+            },
+            '\x1B' if self.state != State::Osc => {
+                self.state = State::Escape;
+                return None;
+            },
+            _ => {}
+        }
+
+        match self.state {
+            State::Ground => Some(Sequence::Print(c)),
+            State::Escape => self.escape(c),
+            State::EscapeIntermediate => {
+                if !is_intermediate(c) {
+                    self.state = State::Ground;
+                }
+                None
+            },
+            State::Csi => self.csi(c),
+            State::CsiIgnore => {
+                if is_final(c) {
+                    self.state = State::Ground;
+                }
+                None
+            },
+            State::Osc => {
+                match c {
+                    '\x07' => self.state = State::Ground,
+                    '\x1B' => self.state = State::OscEscape,
+                    _ => {}
+                }
+                None
+            },
+            State::OscEscape => {
+                // ESC \ is the proper terminator, anything else ends it too.
+                self.state = State::Ground;
+                None
+            },
+        }
+    }
+
+    fn escape(&mut self, c: char) -> Option<Sequence> {
+        self.state = State::Ground;
+        match c {
+            '[' => {
+                self.params = Params::new();
+                self.digits = false;
+                self.state = State::Csi;
+                None
+            },
+            ']' => {
+                self.state = State::Osc;
+                None
+            },
+            '7' => Some(Sequence::SaveCursor),
+            '8' => Some(Sequence::RestoreCursor),
+            'D' => Some(Sequence::Index),
+            'E' => Some(Sequence::NextLine),
+            'M' => Some(Sequence::ReverseIndex),
+            'c' => Some(Sequence::Reset),
+            c if is_intermediate(c) => {
+                self.state = State::EscapeIntermediate;
+                None
+            },
+            _ => None,
+        }
+    }
+
+    fn csi(&mut self, c: char) -> Option<Sequence> {
+        match c {
+            '0'..='9' => {
+                let params = &mut self.params;
+                if params.count == 0 {
+                    params.count = 1;
+                }
+                if params.count <= MAX_PARAMS {
+                    let v = &mut params.values[params.count - 1];
+                    *v = v.saturating_mul(10).saturating_add(c as u32 - '0' as u32);
+                }
+                self.digits = true;
+                None
+            },
+            ';' => {
+                if self.params.count == 0 {
+                    self.params.count = 1;
+                }
+                if self.params.count < MAX_PARAMS {
+                    self.params.count += 1;
+                }
+                self.digits = false;
+                None
+            },
+            // Private modes (e.g. ?25l to hide the cursor) and intermediates
+            // mean something we don't do.
+            '<'..='?' | ' '..='/' => {
+                self.state = State::CsiIgnore;
+                None
+            },
+            c if is_final(c) => {
+                self.state = State::Ground;
+                self.dispatch(c)
+            },
+            // Control characters inside a sequence are ignored.
+            _ => None,
+        }
+    }
+
+    fn dispatch(&self, c: char) -> Option<Sequence> {
+        let p = &self.params;
+        let n = p.get_or(0, 1);
+        Some(match c {
+            'A' => Sequence::CursorUp(n),
+            'B' | 'e' => Sequence::CursorDown(n),
+            'C' | 'a' => Sequence::CursorForward(n),
+            'D' => Sequence::CursorBack(n),
+            'E' => Sequence::CursorNextLine(n),
+            'F' => Sequence::CursorPrevLine(n),
+            'G' | '`' => Sequence::CursorColumn(n - 1),
+            'd' => Sequence::CursorRow(n - 1),
+            'H' | 'f' => Sequence::CursorTo{row: n - 1, col: p.get_or(1, 1) - 1},
+            'J' => Sequence::EraseDisplay(p.get_or(0, 0)),
+            'K' => Sequence::EraseLine(p.get_or(0, 0)),
+            'm' => Sequence::Graphics(*p),
+            'S' => Sequence::ScrollUp(n),
+            'T' => Sequence::ScrollDown(n),
+            'r' => {
+                let bottom = match p.get_or(1, 0) {
+                    0 => None,
+                    b => Some(b - 1),
+                };
+                Sequence::ScrollRegion{top: p.get_or(0, 1) - 1, bottom: bottom}
+            },
+            's' => Sequence::SaveCursor,
+            'u' => Sequence::RestoreCursor,
+            _ => return None,
+        })
+    }
+}
+
+// ANSI colour numbers (as in SGR 30-37, then the bright 90-97) to VGA colours.
+static ANSI_COLOURS: [Colour; 16] = [
+    Colour::Black, Colour::Red, Colour::Green, Colour::Brown,
+    Colour::Blue, Colour::Pink, Colour::Cyan, Colour::LightGray,
+    Colour::DarkGray, Colour::LightRed, Colour::LightGreen, Colour::Yellow,
+    Colour::LightBlue, Colour::LightPink, Colour::LightCyan, Colour::White,
+];
+
+pub fn ansi_colour(index: u32) -> Colour {
+    ANSI_COLOURS[(index & 0xF) as usize]
+}
+
+// How text is drawn, as changed by SGR sequences.
+#[derive(Copy, Clone)]
+pub struct Rendition {
+    pub fg: Colour,
+    pub bg: Colour,
+    // Bold is shown as the bright version of the foreground colour.
+    pub bold: bool,
+    // Whether fg and bg are currently swapped.
+    pub reversed: bool,
+}
+
+impl Rendition {
+    pub fn new() -> Rendition {
+        Rendition{fg: Colour::LightGray, bg: Colour::Black, bold: false, reversed: false}
+    }
+
+    pub fn apply(&mut self, params: &Params) {
+        // No parameters at all is a reset.
+        if params.len() == 0 {
+            *self = Rendition::new();
+            return;
+        }
+
+        let mut iter = params.iter();
+        while let Some(p) = iter.next() {
+            match p {
+                0 => *self = Rendition::new(),
+                1 => self.set_bold(true),
+                2 | 22 => self.set_bold(false),
+                7 => self.set_reversed(true),
+                27 => self.set_reversed(false),
+                30..=37 => self.set_fg(p - 30),
+                39 => self.set_fg(7),
+                40..=47 => self.set_bg(ansi_colour(p - 40)),
+                49 => self.set_bg(Colour::Black),
+                90..=97 => self.set_fg(p - 90 + 8),
+                100..=107 => self.set_bg(ansi_colour(p - 100 + 8)),
+                38 | 48 => {
+                    // Only the first 16 of the 256 colours can be shown, and
+                    // none of the RGB ones.
+                    let colour = match iter.next() {
+                        Some(5) => iter.next().filter(|&n| n < 16),
+                        Some(2) => {
+                            iter.by_ref().take(3).for_each(drop);
+                            None
+                        },
+                        _ => None,
+                    };
+
+                    match (p, colour) {
+                        (38, Some(n)) => self.set_fg(n),
+                        (48, Some(n)) => self.set_bg(ansi_colour(n)),
+                        _ => {}
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
+    fn set_fg(&mut self, index: u32) {
+        let index = if self.bold { index | 8 } else { index };
+        if self.reversed {
+            self.bg = ansi_colour(index);
+        } else {
+            self.fg = ansi_colour(index);
+        }
+    }
+
+    fn set_bg(&mut self, colour: Colour) {
+        if self.reversed {
+            self.fg = colour;
+        } else {
+            self.bg = colour;
+        }
+    }
+
+    fn set_bold(&mut self, bold: bool) {
+        if self.bold == bold {
+            return;
+        }
+
+        self.bold = bold;
+        let fg = if self.reversed { &mut self.bg } else { &mut self.fg };
+        let vga = if bold { *fg as u8 | 8 } else { *fg as u8 & 7 };
+        *fg = vga_colour(vga);
+    }
+
+    fn set_reversed(&mut self, reversed: bool) {
+        if self.reversed != reversed {
+            core::mem::swap(&mut self.fg, &mut self.bg);
+            self.reversed = reversed;
+        }
+    }
+}
+
+// VGA colour numbers back to colours.
+fn vga_colour(value: u8) -> Colour {
+    // The bright colours are the normal ones with bit 3 set in both schemes,
+    // and only the red and blue bits are swapped.
+    let value = value & 0xF;
+    let ansi = (value & 0b1010) | ((value & 1) << 2) | ((value >> 2) & 1);
+    ansi_colour(ansi as u32)
+}
+
+fn is_intermediate(c: char) -> bool {
+    (' '..='/').contains(&c)
+}
+
+fn is_final(c: char) -> bool {
+    ('@'..='~').contains(&c)
+}
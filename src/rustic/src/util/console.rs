@@ -0,0 +1,158 @@
+/*
+ * Copyright (c) 2013 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Formatted output to the screen and serial port, for code that doesn't
+// have the kernel to hand. The print macros lock the kernel themselves; code
+// that already holds it can use ScreenWriter or SerialWriter with write!.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+use crate::Kernel;
+use crate::mach::{Machine, Screen, Serial};
+use crate::util::colour::Colour;
+use crate::util::sync::Spinlock;
+
+// Screen output from while the kernel was locked, waiting for the next print
+// that can take the lock.
+static PENDING: Spinlock<String> = Spinlock::new(String::new());
+
+pub struct ScreenWriter<'a, T: Screen + ?Sized>(pub &'a mut T);
+
+impl<T: Screen + ?Sized> Write for ScreenWriter<'_, T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.screen_write(s);
+        Ok(())
+    }
+}
+
+pub struct SerialWriter<'a, T: Serial + ?Sized>(pub &'a T);
+
+impl<T: Serial + ?Sized> Write for SerialWriter<'_, T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.serial_write(s);
+        Ok(())
+    }
+}
+
+// Runs f with the kernel locked, unless it already is, which (with only one
+// CPU and interrupts off while it's held) means our caller has it.
+fn with_kernel<F: FnOnce(&mut Kernel)>(f: F) -> bool {
+    let locked_kernel = match Kernel::optional_kernel() {
+        Some(k) => k,
+        None => return false,
+    };
+
+    // Not the tail expression, so the guard goes before locked_kernel.
+    let result = match locked_kernel.try_lock() {
+        Ok(mut kernel) => {
+            f(&mut kernel);
+            true
+        },
+        Err(_) => false,
+    };
+    result
+}
+
+// Writes to the screen, in the given foreground colour if any.
+pub(crate) fn write_screen(text: &str, colour: Option<Colour>) {
+    let printed = with_kernel(|kernel| {
+        let pending = core::mem::replace(&mut *PENDING.lock().unwrap(), String::new());
+        kernel.screen_write(&pending);
+
+        match colour {
+            Some(colour) => {
+                kernel.screen_save_attrib();
+                let (_, bg) = kernel.screen_colours();
+                kernel.screen_attrib(colour, bg);
+                kernel.screen_write(text);
+                kernel.screen_restore_attrib();
+            },
+            None => kernel.screen_write(text),
+        }
+    });
+
+    if !printed {
+        // Errors lose their colour if they have to wait, but not their text.
+        PENDING.lock().unwrap().push_str(text);
+    }
+}
+
+// Writes to the serial port used for debugging.
+pub(crate) fn write_serial(text: &str) {
+    let printed = with_kernel(|kernel| kernel.serial_write(text));
+    if !printed {
+        // Straight out of the port, past anything still buffered.
+        Kernel::debug(text);
+    }
+}
+
+fn format(args: fmt::Arguments) -> String {
+    let mut text = String::new();
+    let _ = text.write_fmt(args);
+    text
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    write_screen(&format(args), None);
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    write_screen(&format(args), Some(Colour::LightRed));
+}
+
+#[doc(hidden)]
+pub fn _serial_print(args: fmt::Arguments) {
+    write_serial(&format(args));
+}
+
+// Prints to the screen.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::util::console::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::util::console::_print(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+// Prints to the screen in red.
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::util::console::_eprint(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::util::console::_eprint(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+// Prints to the serial port used for debugging.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::util::console::_serial_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::util::console::_serial_print(format_args!("{}\n", format_args!($($arg)*))));
+}
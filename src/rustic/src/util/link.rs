@@ -0,0 +1,117 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Sending and receiving whole messages over the serial line (see the framing
+// crate for what goes over the wire). Anything else written to the port,
+// such as debug output, is skipped over by the other end, as long as it
+// isn't written in the middle of a frame.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use framing::{Event, Link, Port};
+
+use crate::Kernel;
+use crate::arch::Threads;
+use crate::mach::Serial;
+
+pub use framing::{Checksum, Config, MAX_PAYLOAD};
+
+// How often to check for an acknowledgement.
+static POLL_MS: usize = 10;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SendError {
+    // More than MAX_PAYLOAD bytes.
+    TooLong,
+    // A reliable message wasn't acknowledged, even after retrying.
+    NoAck,
+}
+
+pub struct SerialLink {
+    link: Link,
+    // Messages that arrived while we were waiting for an acknowledgement.
+    inbox: VecDeque<Vec<u8>>,
+}
+
+// Writes to the debug port.
+pub(crate) struct DebugPort;
+
+impl Port for DebugPort {
+    fn write(&mut self, bytes: &[u8]) {
+        Kernel::kernel().lock().unwrap().serial_write_bytes(bytes);
+    }
+}
+
+impl SerialLink {
+    pub fn new(config: Config) -> SerialLink {
+        SerialLink{link: Link::new(config), inbox: VecDeque::new()}
+    }
+
+    // Reliable messages block until the other end acknowledges them. None
+    // of these may be called with the kernel locked.
+    pub fn send(&mut self, msg: &[u8], reliable: bool) -> Result<(), SendError> {
+        if let Err(_) = self.link.send(&mut DebugPort, msg, reliable, now()) {
+            return Err(SendError::TooLong);
+        }
+
+        while self.link.busy() {
+            let byte = Kernel::kernel().lock().unwrap().serial_try_read();
+            match byte {
+                Some(byte) => self.feed(byte),
+                None => {
+                    if let Some(Event::Failed) = self.link.poll(&mut DebugPort, now()) {
+                        return Err(SendError::NoAck);
+                    }
+
+                    Kernel::sleep_ms(Kernel::kernel(), POLL_MS);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Blocks until a message arrives.
+    pub fn receive(&mut self) -> Vec<u8> {
+        loop {
+            if let Some(msg) = self.inbox.pop_front() {
+                return msg;
+            }
+
+            self.feed(Kernel::serial_read());
+        }
+    }
+
+    pub fn try_receive(&mut self) -> Option<Vec<u8>> {
+        while self.inbox.is_empty() {
+            let byte = Kernel::kernel().lock().unwrap().serial_try_read()?;
+            self.feed(byte);
+        }
+
+        self.inbox.pop_front()
+    }
+
+    fn feed(&mut self, byte: u8) {
+        if let Some(Event::Message(msg)) = self.link.receive(&mut DebugPort, byte) {
+            self.inbox.push_back(msg.to_vec());
+        }
+    }
+}
+
+fn now() -> u64 {
+    Kernel::kernel().lock().unwrap().now().as_ms()
+}
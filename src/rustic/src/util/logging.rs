@@ -0,0 +1,212 @@
+/*
+ * Copyright (c) 2013 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// A logger for the `log` crate's macros. Each line carries the uptime, the
+// running thread and where it came from, and goes to every sink that wants
+// its level:
+//
+//     [   12.345] 3 WARN  rustic::mach::pc::pic: spurious IRQ 7
+//
+// The kernel sets this up at start with the serial port and dmesg sinks, at
+// Info. Logging is fine with the kernel locked and from interrupt handlers.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+pub use log::{debug, error, info, trace, warn, Level, LevelFilter};
+use log::{Log, Metadata, Record};
+
+use crate::Kernel;
+use crate::arch::Threads;
+use crate::util::colour::Colour;
+use crate::util::console;
+use crate::util::sync::Spinlock;
+
+// Lines kept by dmesg, unless changed with set_dmesg_lines().
+static DEFAULT_DMESG_LINES: usize = 256;
+
+// Somewhere log lines go.
+pub trait Sink: Send {
+    // The line is complete, with its newline.
+    fn write(&mut self, level: Level, line: &str);
+}
+
+// The serial port used for debugging.
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn write(&mut self, _: Level, line: &str) {
+        console::write_serial(line);
+    }
+}
+
+// The screen, with errors and warnings picked out in colour.
+pub struct ScreenSink;
+
+impl Sink for ScreenSink {
+    fn write(&mut self, level: Level, line: &str) {
+        let colour = match level {
+            Level::Error => Some(Colour::LightRed),
+            Level::Warn => Some(Colour::Yellow),
+            _ => None,
+        };
+        console::write_screen(line, colour);
+    }
+}
+
+// The most recent lines, kept in memory for dmesg().
+pub struct DmesgSink;
+
+impl Sink for DmesgSink {
+    fn write(&mut self, _: Level, line: &str) {
+        let mut dmesg = DMESG.lock().unwrap();
+        if dmesg.max_lines == 0 {
+            return;
+        }
+
+        while dmesg.lines.len() >= dmesg.max_lines {
+            dmesg.lines.pop_front();
+        }
+        dmesg.lines.push_back(String::from(line));
+    }
+}
+
+struct Dmesg {
+    lines: VecDeque<String>,
+    max_lines: usize,
+}
+
+static DMESG: Spinlock<Dmesg> = Spinlock::new(Dmesg{
+    lines: VecDeque::new(),
+    max_lines: DEFAULT_DMESG_LINES,
+});
+
+struct State {
+    sinks: Vec<(Box<dyn Sink>, LevelFilter)>,
+    level: LevelFilter,
+    // Module path prefixes with their own level. The longest match wins.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl State {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let matches = |module: &str| {
+            target == module || (target.starts_with(module) && target[module.len()..].starts_with("::"))
+        };
+
+        self.modules.iter()
+            .filter(|(module, _)| matches(module))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    // Lets the log macros skip anything nobody could want.
+    fn update_max_level(&self) {
+        let most = self.modules.iter().map(|&(_, level)| level).fold(self.level, |a, b| a.max(b));
+        let sinks = self.sinks.iter().map(|&(_, level)| level).fold(LevelFilter::Off, |a, b| a.max(b));
+        log::set_max_level(most.min(sinks));
+    }
+}
+
+struct Logger {
+    state: Spinlock<State>,
+}
+
+static LOGGER: Logger = Logger{
+    state: Spinlock::new(State{
+        sinks: Vec::new(),
+        level: LevelFilter::Info,
+        modules: Vec::new(),
+    }),
+};
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.state.lock().unwrap().level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        let mut state = self.state.lock().unwrap();
+        if record.level() > state.level_for(record.target()) {
+            return;
+        }
+
+        let ms = Kernel::now_static().as_ms();
+        let mut line = String::new();
+        let _ = write!(line, "[{:5}.{:03}] {} {:5} {}: {}\n",
+            ms / 1000, ms % 1000, Kernel::thread_id_static(), record.level(), record.target(), record.args());
+
+        for (sink, level) in state.sinks.iter_mut() {
+            if record.level() <= *level {
+                sink.write(record.level(), &line);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Installs the logger with the serial and dmesg sinks. Returns false if
+// there's already a logger.
+pub fn init() -> bool {
+    if log::set_logger(&LOGGER).is_err() {
+        return false;
+    }
+
+    add_sink(Box::new(SerialSink), LevelFilter::Trace);
+    add_sink(Box::new(DmesgSink), LevelFilter::Trace);
+    true
+}
+
+// Sends lines up to the given level to another sink.
+pub fn add_sink(sink: Box<dyn Sink>, level: LevelFilter) {
+    let mut state = LOGGER.state.lock().unwrap();
+    state.sinks.push((sink, level));
+    state.update_max_level();
+}
+
+// The level for modules without one of their own.
+pub fn set_level(level: LevelFilter) {
+    let mut state = LOGGER.state.lock().unwrap();
+    state.level = level;
+    state.update_max_level();
+}
+
+// The level for a module and everything under it, e.g. "rustic::mach::pc".
+pub fn set_module_level(module: &str, level: LevelFilter) {
+    let mut state = LOGGER.state.lock().unwrap();
+    match state.modules.iter_mut().find(|(m, _)| m == module) {
+        Some(entry) => entry.1 = level,
+        None => state.modules.push((String::from(module), level)),
+    }
+    state.update_max_level();
+}
+
+// Everything in the dmesg buffer, oldest first.
+pub fn dmesg() -> Vec<String> {
+    DMESG.lock().unwrap().lines.iter().cloned().collect()
+}
+
+pub fn set_dmesg_lines(lines: usize) {
+    let mut dmesg = DMESG.lock().unwrap();
+    dmesg.max_lines = lines;
+    while dmesg.lines.len() > lines {
+        dmesg.lines.pop_front();
+    }
+}
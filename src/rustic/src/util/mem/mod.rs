@@ -15,22 +15,101 @@
  */
 
 use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
+use core::ptr;
 
-use simplealloc;
+use simplealloc::Heap;
+use simplealloc::frames::{FrameBitmap, FRAME_SIZE};
 
-// TODO: write a proper allocator, prime with multiboot memory map.
+use crate::util::sync::Spinlock;
+
+// Minimum number of frames to pull into the heap when it runs dry.
+static HEAP_GROW_FRAMES: usize = 64;
+
+static FRAMES: Spinlock<FrameBitmap> = Spinlock::new(FrameBitmap::new());
+static HEAP: Spinlock<Heap> = Spinlock::new(Heap::new());
 
 struct RusticAllocator;
 
 unsafe impl GlobalAlloc for RusticAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        simplealloc::direct_alloc(layout.size(), layout.align())
+        let result = HEAP.lock().unwrap().allocate(layout.size(), layout.align());
+        if !result.is_null() {
+            return result;
+        }
+
+        // Out of heap - pull in some more physical memory and try again.
+        if grow_heap(layout.size() + layout.align()) {
+            HEAP.lock().unwrap().allocate(layout.size(), layout.align())
+        } else {
+            result
+        }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        simplealloc::direct_dealloc(ptr)
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        HEAP.lock().unwrap().deallocate(ptr, layout.size(), layout.align())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if HEAP.lock().unwrap().reallocate_in_place(ptr, layout.size(), new_size, layout.align()) {
+            return ptr;
+        }
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let result = self.alloc(new_layout);
+        if !result.is_null() {
+            ptr::copy_nonoverlapping(ptr, result, cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+
+        result
     }
 }
 
 #[global_allocator]
 static A: RusticAllocator = RusticAllocator;
+
+fn grow_heap(bytes: usize) -> bool {
+    let needed = (bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+
+    // Try for a decent chunk first so we aren't back here on every
+    // allocation, but settle for just what's needed if memory is tight.
+    let preferred = cmp::max(needed, HEAP_GROW_FRAMES);
+    let (base, count) = match alloc_contiguous(preferred) {
+        Some(base) => (base, preferred),
+        None => match alloc_contiguous(needed) {
+            Some(base) => (base, needed),
+            None => return false
+        }
+    };
+
+    unsafe { HEAP.lock().unwrap().add_region(base, count * FRAME_SIZE) };
+    true
+}
+
+// Called by the machine layer to describe the physical memory available.
+// This must be done before the first heap allocation.
+pub fn init_frames<F: FnOnce(&mut FrameBitmap)>(f: F) {
+    let mut frames = FRAMES.lock().unwrap();
+    f(&mut frames);
+}
+
+pub fn alloc_frame() -> Option<usize> {
+    FRAMES.lock().unwrap().alloc_frame()
+}
+
+pub fn free_frame(addr: usize) {
+    FRAMES.lock().unwrap().free_frame(addr)
+}
+
+pub fn alloc_contiguous(count: usize) -> Option<usize> {
+    FRAMES.lock().unwrap().alloc_contiguous(count)
+}
+
+pub fn free_frame_count() -> usize {
+    FRAMES.lock().unwrap().free_count()
+}
+
+pub fn memory_top() -> usize {
+    FRAMES.lock().unwrap().memory_top()
+}
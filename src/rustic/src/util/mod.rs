@@ -16,8 +16,15 @@
 
 #![macro_use]
 
+pub mod ansi;
+pub mod console;
+pub mod link;
+pub mod logging;
 pub mod mem;
+pub mod shell;
 pub mod sync;
+pub mod time;
+pub mod xmodem;
 
 pub mod colour {
     #[derive(Copy, Clone)]
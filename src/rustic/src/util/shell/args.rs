@@ -0,0 +1,109 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Splitting a command line into words, and picking them apart.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub struct Args {
+    // The command name, then its arguments.
+    words: Vec<String>,
+}
+
+impl Args {
+    // Words are split on whitespace, except within double quotes. A
+    // backslash takes the next character as-is. Returns None if a quote
+    // isn't closed.
+    pub fn parse(line: &str) -> Option<Args> {
+        let mut words = Vec::new();
+        let mut word = String::new();
+        let mut in_word = false;
+        let mut quoted = false;
+
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    word.extend(chars.next());
+                    in_word = true;
+                },
+                '"' => {
+                    quoted = !quoted;
+                    in_word = true;
+                },
+                c if c.is_whitespace() && !quoted => {
+                    if in_word {
+                        words.push(core::mem::replace(&mut word, String::new()));
+                        in_word = false;
+                    }
+                },
+                c => {
+                    word.push(c);
+                    in_word = true;
+                }
+            }
+        }
+
+        if quoted {
+            return None;
+        }
+
+        if in_word {
+            words.push(word);
+        }
+
+        Some(Args{words: words})
+    }
+
+    // None for an empty line.
+    pub fn name(&self) -> Option<&str> {
+        self.words.first().map(|w| w.as_str())
+    }
+
+    // How many arguments there are, not counting the command name.
+    pub fn len(&self) -> usize {
+        self.words.len().saturating_sub(1)
+    }
+
+    // Arguments count from zero.
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.words.get(index + 1).map(|w| w.as_str())
+    }
+
+    // Takes decimal, or hex with a leading 0x. None if the argument is
+    // missing or isn't a number.
+    pub fn number(&self, index: usize) -> Option<usize> {
+        parse_number(self.get(index)?)
+    }
+
+    // As number(), but a missing argument gives the default. Still None if
+    // the argument is there but isn't a number.
+    pub fn number_or(&self, index: usize, default: usize) -> Option<usize> {
+        match self.get(index) {
+            Some(arg) => parse_number(arg),
+            None => Some(default)
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Option<usize> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        usize::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
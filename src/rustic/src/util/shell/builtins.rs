@@ -0,0 +1,241 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Commands every shell has.
+
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::Kernel;
+use crate::arch::{Threads, VirtualMemory};
+use crate::arch::threads::ThreadState;
+use crate::mach::{HardwareTimer, IoPort, IrqRegister, Machine, TimerHandlers};
+use crate::util::logging::{self, LevelFilter};
+
+use super::{Args, Output, Shell};
+
+// More than this is hard to read back anyway.
+static MAX_DUMP: usize = 4096;
+
+pub fn register(shell: &mut Shell) {
+    shell.register("threads", "", "lists threads", threads);
+    shell.register("dump", "<address> [length]", "shows memory in hex", dump);
+    shell.register("in", "<port> [8|16|32]", "reads an I/O port", port_in);
+    shell.register("out", "<port> <value> [8|16|32]", "writes an I/O port", port_out);
+    shell.register("irqs", "", "lists IRQs with handlers", irqs);
+    shell.register("timers", "", "lists timer handlers", timers);
+    shell.register("dmesg", "", "shows recent log messages", dmesg);
+    shell.register("loglevel", "<off|error|warn|info|debug|trace> [module]", "sets what gets logged", loglevel);
+    shell.register("reboot", "", "resets the machine", reboot);
+}
+
+fn threads(out: &mut Output, args: &Args) -> bool {
+    if args.len() != 0 {
+        return false;
+    }
+
+    let mut list = Kernel::kernel().lock().unwrap().thread_list();
+    list.sort_by_key(|t| t.id);
+
+    let _ = write!(out, "   ID  STATE\n");
+    for thread in list {
+        let state = match thread.state {
+            ThreadState::Running => "running",
+            ThreadState::Ready => "ready",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Sleeping => "sleeping",
+        };
+        let _ = write!(out, "{:5}  {}\n", thread.id, state);
+    }
+
+    true
+}
+
+fn dump(out: &mut Output, args: &Args) -> bool {
+    let (addr, len) = match (args.number(0), args.number_or(1, 64)) {
+        (Some(addr), Some(len)) if args.len() <= 2 => (addr, len.min(MAX_DUMP)),
+        _ => return false
+    };
+
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false
+    };
+
+    // Copy it out with the lock held, so the mappings can't change under us.
+    let mut bytes = Vec::with_capacity(len);
+    {
+        let kernel = Kernel::kernel();
+        let kernel = kernel.lock().unwrap();
+        let page_size = kernel.page_size();
+        for a in addr..end {
+            if a == addr || a % page_size == 0 {
+                if kernel.translate(a).is_none() {
+                    break;
+                }
+            }
+            bytes.push(unsafe { *(a as *const u8) });
+        }
+    }
+
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", addr + i * 16);
+        for b in line {
+            let _ = write!(out, " {:02x}", b);
+        }
+        for _ in line.len()..16 {
+            out.write("   ");
+        }
+
+        out.write("  ");
+        for &b in line {
+            let c = if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' };
+            let _ = write!(out, "{}", c);
+        }
+        out.write("\n");
+    }
+
+    if bytes.len() < len {
+        let _ = write!(out, "{:#010x} is not mapped\n", addr + bytes.len());
+    }
+
+    true
+}
+
+fn port_width(args: &Args, index: usize) -> Option<usize> {
+    match args.number_or(index, 8) {
+        Some(width @ 8) | Some(width @ 16) | Some(width @ 32) => Some(width),
+        _ => None
+    }
+}
+
+fn port_in(out: &mut Output, args: &Args) -> bool {
+    let (port, width) = match (args.number(0), port_width(args, 1)) {
+        (Some(port), Some(width)) if port <= 0xFFFF && args.len() <= 2 => (port as u16, width),
+        _ => return false
+    };
+
+    let value = {
+        let kernel = Kernel::kernel();
+        let kernel = kernel.lock().unwrap();
+        match width {
+            8 => kernel.inport::<u8>(port) as u32,
+            16 => kernel.inport::<u16>(port) as u32,
+            _ => kernel.inport::<u32>(port),
+        }
+    };
+
+    let _ = write!(out, "{:#06x}: {:#0w$x}\n", port, value, w = width / 4 + 2);
+    true
+}
+
+fn port_out(_: &mut Output, args: &Args) -> bool {
+    let (port, value, width) = match (args.number(0), args.number(1), port_width(args, 2)) {
+        (Some(port), Some(value), Some(width)) if port <= 0xFFFF && args.len() <= 3 => (port as u16, value, width),
+        _ => return false
+    };
+
+    if width < 32 && value >> width != 0 {
+        return false;
+    }
+
+    let kernel = Kernel::kernel();
+    let kernel = kernel.lock().unwrap();
+    match width {
+        8 => kernel.outport(port, value as u8),
+        16 => kernel.outport(port, value as u16),
+        _ => kernel.outport(port, value as u32),
+    }
+
+    true
+}
+
+fn irqs(out: &mut Output, args: &Args) -> bool {
+    if args.len() != 0 {
+        return false;
+    }
+
+    let list = Kernel::kernel().lock().unwrap().irq_handlers();
+
+    let _ = write!(out, "IRQ  HANDLERS          TRIGGER\n");
+    for irq in list {
+        let handlers = match (irq.immediate, irq.deferred) {
+            (true, true) => "immediate+deferred",
+            (true, false) => "immediate",
+            (false, true) => "deferred",
+            (false, false) => "none",
+        };
+        let trigger = if irq.level_trigger { "level" } else { "edge" };
+        let _ = write!(out, "{:3}  {:18} {}\n", irq.irq, handlers, trigger);
+    }
+
+    true
+}
+
+fn timers(out: &mut Output, args: &Args) -> bool {
+    if args.len() != 0 {
+        return false;
+    }
+
+    let (hz, list) = {
+        let kernel = Kernel::kernel();
+        let kernel = kernel.lock().unwrap();
+        (kernel.timer_hz(), kernel.timers())
+    };
+
+    let _ = write!(out, "Ticking at {} Hz, {} handler(s)\n", hz, list.len());
+    for f in list {
+        let _ = write!(out, "  {:#010x}\n", f as usize);
+    }
+
+    true
+}
+
+fn dmesg(out: &mut Output, args: &Args) -> bool {
+    if args.len() != 0 {
+        return false;
+    }
+
+    for line in logging::dmesg() {
+        out.write(&line);
+    }
+
+    true
+}
+
+fn loglevel(_: &mut Output, args: &Args) -> bool {
+    let level: LevelFilter = match args.get(0).map(|l| l.parse()) {
+        Some(Ok(level)) => level,
+        _ => return false
+    };
+
+    match (args.get(1), args.len()) {
+        (None, 1) => logging::set_level(level),
+        (Some(module), 2) => logging::set_module_level(module, level),
+        _ => return false
+    }
+
+    true
+}
+
+fn reboot(out: &mut Output, args: &Args) -> bool {
+    if args.len() != 0 {
+        return false;
+    }
+
+    out.write("Rebooting...\n");
+    Kernel::kernel().lock().unwrap().reboot();
+}
@@ -0,0 +1,315 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Line editing and history for the shell, and turning what the console
+// sends into editing keys.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::mach::keys::{self, KeyEvent};
+
+static HISTORY_SIZE: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    // ^C - give up on the line.
+    Cancel,
+    // ^U - clear the line.
+    Kill,
+    Ignored,
+}
+
+// What the shell needs to do after a key.
+pub enum Edit {
+    Done(String),
+    Cancelled,
+    // The character was added at the end of the line, and just needs
+    // echoing.
+    Append(char),
+    // Anything else. Carries how long the line was before, so that any
+    // leftovers can be wiped.
+    Redraw(usize),
+    Nothing,
+}
+
+pub struct LineEditor {
+    line: Vec<char>,
+    cursor: usize,
+    max_len: usize,
+    // Most recent last.
+    history: VecDeque<String>,
+    // Which history entry is being shown, if any, and the line that was
+    // being typed before going back through history.
+    browsing: Option<usize>,
+    pending: Vec<char>,
+}
+
+// Terminals send special keys as escape sequences.
+enum Escape {
+    None,
+    // Seen ESC.
+    Start,
+    // Seen ESC [ or ESC O, and maybe some parameter digits.
+    Sequence(u8),
+}
+
+pub struct SerialDecoder {
+    escape: Escape,
+    // Swallows the LF of a CR LF.
+    last_cr: bool,
+    // A UTF-8 sequence being put back together.
+    utf8: [u8; 4],
+    utf8_len: usize,
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        LineEditor{
+            line: Vec::new(),
+            cursor: 0,
+            max_len: 0,
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            browsing: None,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+    }
+
+    pub fn line(&self) -> &[char] {
+        &self.line
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn edit(&mut self, key: Key) -> Edit {
+        let old_len = self.line.len();
+
+        match key {
+            Key::Char(c) => {
+                if self.line.len() >= self.max_len {
+                    return Edit::Nothing;
+                }
+
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+                if self.cursor == self.line.len() {
+                    return Edit::Append(c);
+                }
+            },
+            Key::Enter => {
+                let line: String = self.line.iter().collect();
+                self.reset();
+
+                if !line.trim().is_empty() && self.history.back() != Some(&line) {
+                    if self.history.len() == HISTORY_SIZE {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(line.clone());
+                }
+
+                return Edit::Done(line);
+            },
+            Key::Cancel => {
+                self.reset();
+                return Edit::Cancelled;
+            },
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            },
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            },
+            Key::Left if self.cursor > 0 => self.cursor -= 1,
+            Key::Right if self.cursor < self.line.len() => self.cursor += 1,
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::Kill => {
+                self.line.clear();
+                self.cursor = 0;
+            },
+            Key::Up => {
+                let index = match self.browsing {
+                    Some(0) => return Edit::Nothing,
+                    Some(index) => index - 1,
+                    None if self.history.is_empty() => return Edit::Nothing,
+                    None => {
+                        self.pending = self.line.clone();
+                        self.history.len() - 1
+                    }
+                };
+
+                self.browsing = Some(index);
+                self.show(self.history[index].chars().collect());
+            },
+            Key::Down => {
+                match self.browsing {
+                    Some(index) if index + 1 < self.history.len() => {
+                        self.browsing = Some(index + 1);
+                        self.show(self.history[index + 1].chars().collect());
+                    },
+                    Some(_) => {
+                        self.browsing = None;
+                        let pending = core::mem::replace(&mut self.pending, Vec::new());
+                        self.show(pending);
+                    },
+                    None => return Edit::Nothing
+                }
+            },
+            _ => return Edit::Nothing
+        }
+
+        Edit::Redraw(old_len)
+    }
+
+    fn show(&mut self, mut line: Vec<char>) {
+        line.truncate(self.max_len);
+        self.cursor = line.len();
+        self.line = line;
+    }
+
+    fn reset(&mut self) {
+        self.line.clear();
+        self.cursor = 0;
+        self.browsing = None;
+        self.pending.clear();
+    }
+}
+
+impl SerialDecoder {
+    pub fn new() -> SerialDecoder {
+        SerialDecoder{escape: Escape::None, last_cr: false, utf8: [0; 4], utf8_len: 0}
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Key {
+        let last_cr = self.last_cr;
+        self.last_cr = byte == b'\r';
+
+        match self.escape {
+            Escape::Start => {
+                self.escape = match byte {
+                    b'[' | b'O' => Escape::Sequence(0),
+                    _ => Escape::None
+                };
+                return Key::Ignored;
+            },
+            Escape::Sequence(param) => {
+                if byte.is_ascii_digit() {
+                    self.escape = Escape::Sequence(param.saturating_mul(10).saturating_add(byte - b'0'));
+                    return Key::Ignored;
+                }
+
+                self.escape = Escape::None;
+                return match (byte, param) {
+                    (b'A', _) => Key::Up,
+                    (b'B', _) => Key::Down,
+                    (b'C', _) => Key::Right,
+                    (b'D', _) => Key::Left,
+                    (b'H', _) | (b'~', 1) | (b'~', 7) => Key::Home,
+                    (b'F', _) | (b'~', 4) | (b'~', 8) => Key::End,
+                    (b'~', 3) => Key::Delete,
+                    _ => Key::Ignored
+                };
+            },
+            Escape::None => {}
+        }
+
+        if byte == 0x1B {
+            self.escape = Escape::Start;
+            return Key::Ignored;
+        }
+
+        if byte == b'\n' && last_cr {
+            return Key::Ignored;
+        }
+
+        if byte < 0x80 {
+            self.utf8_len = 0;
+            return char_key(byte as char);
+        }
+
+        // Start of a new sequence, or the next byte of one.
+        if byte & 0xC0 != 0x80 {
+            self.utf8_len = 0;
+        }
+
+        if self.utf8_len < self.utf8.len() {
+            self.utf8[self.utf8_len] = byte;
+            self.utf8_len += 1;
+        }
+
+        match core::str::from_utf8(&self.utf8[..self.utf8_len]) {
+            Ok(s) => {
+                self.utf8_len = 0;
+                s.chars().next().map_or(Key::Ignored, Key::Char)
+            },
+            Err(_) => Key::Ignored
+        }
+    }
+}
+
+pub fn keyboard_key(event: KeyEvent) -> Key {
+    if !event.pressed {
+        return Key::Ignored;
+    }
+
+    match event.keycode {
+        keys::UP => Key::Up,
+        keys::DOWN => Key::Down,
+        keys::LEFT => Key::Left,
+        keys::RIGHT => Key::Right,
+        keys::HOME => Key::Home,
+        keys::END => Key::End,
+        keys::DELETE => Key::Delete,
+        _ => event.ch.map_or(Key::Ignored, char_key)
+    }
+}
+
+// Keyboards and terminals both send these as control characters.
+fn char_key(c: char) -> Key {
+    match c {
+        '\r' | '\n' => Key::Enter,
+        '\x08' | '\x7F' => Key::Backspace,
+        '\x03' => Key::Cancel,
+        '\x15' => Key::Kill,
+        '\x01' => Key::Home,
+        '\x05' => Key::End,
+        '\x02' => Key::Left,
+        '\x06' => Key::Right,
+        '\x10' => Key::Up,
+        '\x0E' => Key::Down,
+        '\x04' => Key::Delete,
+        c if c.is_control() => Key::Ignored,
+        c => Key::Char(c)
+    }
+}
@@ -0,0 +1,247 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// An interactive command shell, run as a kernel thread on a serial port or
+// on the screen and keyboard. Applications can add their own commands
+// alongside the built-in ones:
+//
+//     let mut shell = Shell::new(Console::Serial(1));
+//     shell.register("hello", "<name>", "says hello", hello);
+//     shell.spawn(&mut kernel);
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::Kernel;
+use crate::arch::{JoinHandle, Threads, ThreadSpawn};
+use crate::mach::{Keyboard, Screen, SerialPorts};
+
+mod args;
+mod builtins;
+mod line;
+
+pub use self::args::Args;
+
+use self::line::{Edit, LineEditor, SerialDecoder};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Console {
+    Serial(usize),
+    // Output to the screen, with input from the keyboard.
+    Screen,
+}
+
+// Commands return false if their arguments were no good, and the shell then
+// shows their usage. They can lock the kernel to do their work, but must not
+// write output while holding the lock.
+pub type CommandFn = fn(&mut Output, &Args) -> bool;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    f: CommandFn,
+}
+
+pub struct Shell {
+    console: Console,
+    prompt: String,
+    commands: Vec<Command>,
+    editor: LineEditor,
+}
+
+// Where command output goes.
+pub struct Output {
+    console: Console,
+}
+
+impl Shell {
+    pub fn new(console: Console) -> Shell {
+        let mut shell = Shell{
+            console: console,
+            prompt: String::from("> "),
+            commands: Vec::new(),
+            editor: LineEditor::new(),
+        };
+
+        builtins::register(&mut shell);
+        shell
+    }
+
+    pub fn set_prompt(&mut self, prompt: &str) {
+        self.prompt = String::from(prompt);
+    }
+
+    // Adds a command, replacing any existing one with the same name. The
+    // usage describes its arguments, for help.
+    pub fn register(&mut self, name: &'static str, usage: &'static str, help: &'static str, f: CommandFn) {
+        self.commands.retain(|c| c.name != name);
+        self.commands.push(Command{name: name, usage: usage, help: help, f: f});
+        self.commands.sort_by(|a, b| a.name.cmp(b.name));
+    }
+
+    // Runs the shell on a new thread.
+    pub fn spawn(self, kernel: &mut Kernel) -> JoinHandle<()> {
+        kernel.spawn_thread(move || {
+            let mut shell = self;
+            shell.run();
+        })
+    }
+
+    // Reads and runs commands forever. Must not be called with the kernel
+    // locked.
+    pub fn run(&mut self) -> ! {
+        let mut out = Output{console: self.console};
+        let mut decoder = SerialDecoder::new();
+
+        // Lines are kept to one row so they can be redrawn in place.
+        let width = match self.console {
+            Console::Serial(_) => 80,
+            Console::Screen => Kernel::kernel().lock().unwrap().screen_cols() as usize,
+        };
+        self.editor.set_max_len(width.saturating_sub(self.prompt.chars().count() + 1));
+
+        loop {
+            out.write(&self.prompt);
+            out.show_cursor();
+
+            let line = loop {
+                let key = match self.console {
+                    Console::Serial(port) => match Kernel::serial_read_from(port) {
+                        Some(byte) => decoder.feed(byte),
+                        // No such port, so nobody to talk to.
+                        None => Kernel::thread_terminate(),
+                    },
+                    Console::Screen => line::keyboard_key(Kernel::read_key()),
+                };
+
+                match self.editor.edit(key) {
+                    Edit::Done(line) => break Some(line),
+                    Edit::Cancelled => break None,
+                    Edit::Append(c) => {
+                        let mut bytes = [0u8; 4];
+                        out.write(c.encode_utf8(&mut bytes));
+                    },
+                    Edit::Redraw(old_len) => self.redraw(&mut out, old_len),
+                    Edit::Nothing => continue,
+                }
+
+                out.show_cursor();
+            };
+
+            match line {
+                Some(line) => {
+                    out.write("\n");
+                    self.execute(&mut out, &line);
+                },
+                None => out.write("^C\n"),
+            }
+        }
+    }
+
+    // Writes the line over whatever was there, and then puts the cursor back
+    // where it belongs. Only needs carriage returns, so works anywhere.
+    fn redraw(&self, out: &mut Output, old_len: usize) {
+        let line = self.editor.line();
+        let cursor = self.editor.cursor();
+
+        let mut s = String::from("\r");
+        s.push_str(&self.prompt);
+        s.extend(line.iter());
+        for _ in line.len()..old_len {
+            s.push(' ');
+        }
+
+        s.push('\r');
+        s.push_str(&self.prompt);
+        s.extend(line[..cursor].iter());
+        out.write(&s);
+    }
+
+    fn execute(&self, out: &mut Output, line: &str) {
+        let args = match Args::parse(line) {
+            Some(args) => args,
+            None => {
+                out.write("unterminated quote\n");
+                return;
+            }
+        };
+
+        let name = match args.name() {
+            Some(name) => name,
+            None => return
+        };
+
+        if name == "help" {
+            self.help(out);
+            return;
+        }
+
+        match self.commands.iter().find(|c| c.name == name) {
+            Some(command) => {
+                if !(command.f)(out, &args) {
+                    let _ = write!(out, "usage: {} {}\n", command.name, command.usage);
+                }
+            },
+            None => { let _ = write!(out, "{}: unknown command (try help)\n", name); }
+        }
+    }
+
+    fn help(&self, out: &mut Output) {
+        for command in self.commands.iter() {
+            let _ = write!(out, "{} {}\n    {}\n", command.name, command.usage, command.help);
+        }
+    }
+}
+
+impl Output {
+    pub fn write(&mut self, s: &str) {
+        let locked_kernel = Kernel::kernel();
+        let mut kernel = locked_kernel.lock().unwrap();
+
+        match self.console {
+            Console::Serial(port) => {
+                if let Some(serial) = kernel.serial(port) {
+                    // Terminals want both for a new line.
+                    for (i, part) in s.split('\n').enumerate() {
+                        if i > 0 {
+                            serial.write("\r\n");
+                        }
+                        serial.write(part);
+                    }
+                }
+            },
+            Console::Screen => kernel.screen_write(s),
+        }
+    }
+
+    fn show_cursor(&mut self) {
+        if self.console == Console::Screen {
+            let locked_kernel = Kernel::kernel();
+            let mut kernel = locked_kernel.lock().unwrap();
+            let (x, y) = kernel.screen_position();
+            kernel.screen_cursor(x, y);
+        }
+    }
+}
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s);
+        Ok(())
+    }
+}
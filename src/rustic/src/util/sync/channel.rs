@@ -0,0 +1,313 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Multi-producer, single-consumer channels, along the lines of those in the
+// Rust standard library. Blocking operations park the calling thread, so as
+// with Mutex they must not be used with the kernel locked or from interrupt
+// handlers. IRQ handlers run from the PIC's dispatch thread, so they can
+// send (though they should prefer try_send on a bounded channel).
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::fmt;
+use core::time::Duration;
+
+use super::Spinlock;
+use super::waitqueue::{self, WaitQueue};
+
+pub struct Sender<T> {
+    shared: Arc<Spinlock<Shared<T>>>,
+}
+
+pub struct SyncSender<T> {
+    shared: Arc<Spinlock<Shared<T>>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Spinlock<Shared<T>>>,
+}
+
+struct Shared<T> {
+    queue: VecDeque<T>,
+    // None for unbounded channels.
+    bound: Option<usize>,
+    senders: usize,
+    receiver_alive: bool,
+    // The receiver, when the queue is empty.
+    receiving: WaitQueue,
+    // Senders, when a bounded queue is full.
+    sending: WaitQueue,
+}
+
+// The value that couldn't be sent is handed back.
+pub struct SendError<T>(pub T);
+
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Disconnected,
+}
+
+// Creates a channel that can hold any number of messages, so send() never
+// blocks.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = new_shared(None);
+    (Sender{shared: Arc::clone(&shared)}, Receiver{shared: shared})
+}
+
+// Creates a channel that holds at most `bound` messages, with send()
+// blocking while it is full. The bound must not be zero.
+pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+    if bound == 0 {
+        panic!("sync_channel needs room for at least one message");
+    }
+
+    let shared = new_shared(Some(bound));
+    (SyncSender{shared: Arc::clone(&shared)}, Receiver{shared: shared})
+}
+
+fn new_shared<T>(bound: Option<usize>) -> Arc<Spinlock<Shared<T>>> {
+    Arc::new(Spinlock::new(Shared{
+        queue: VecDeque::new(),
+        bound: bound,
+        senders: 1,
+        receiver_alive: true,
+        receiving: WaitQueue::new(),
+        sending: WaitQueue::new(),
+    }))
+}
+
+impl<T> Shared<T> {
+    fn is_full(&self) -> bool {
+        match self.bound {
+            Some(bound) => self.queue.len() >= bound,
+            None => false
+        }
+    }
+}
+
+// Queues the value if there's room, and wakes the receiver if it's waiting.
+fn try_send<T>(shared: &Spinlock<Shared<T>>, t: T) -> Result<(), TrySendError<T>> {
+    let mut state = shared.lock().unwrap();
+    if !state.receiver_alive {
+        return Err(TrySendError::Disconnected(t));
+    }
+
+    if state.is_full() {
+        return Err(TrySendError::Full(t));
+    }
+
+    state.queue.push_back(t);
+    let receiver = state.receiving.dequeue();
+    drop(state);
+
+    if let Some(id) = receiver {
+        waitqueue::wake(id);
+    }
+
+    Ok(())
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        match try_send(&self.shared, t) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Disconnected(t)) => Err(SendError(t)),
+            Err(TrySendError::Full(_)) => unreachable!("unbounded channel is full"),
+        }
+    }
+}
+
+impl<T> SyncSender<T> {
+    // Blocks while the channel is full.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        let mut t = t;
+        loop {
+            match try_send(&self.shared, t) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(t)) => return Err(SendError(t)),
+                Err(TrySendError::Full(back)) => t = back,
+            }
+
+            let mut state = self.shared.lock().unwrap();
+            if !state.is_full() || !state.receiver_alive {
+                continue;
+            }
+
+            let me = state.sending.enqueue();
+            drop(state);
+
+            waitqueue::block();
+
+            self.shared.lock().unwrap().sending.remove(me);
+        }
+    }
+
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        try_send(&self.shared, t)
+    }
+}
+
+impl<T> Receiver<T> {
+    // Blocks until a message arrives, or every sender has gone away.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            if let Some(me) = self.wait_queue() {
+                waitqueue::block();
+                self.shared.lock().unwrap().receiving.remove(me);
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(t) => {
+                // There's room now for a blocked sender.
+                let sender = state.sending.dequeue();
+                drop(state);
+
+                if let Some(id) = sender {
+                    waitqueue::wake(id);
+                }
+
+                Ok(t)
+            },
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty)
+        }
+    }
+
+    // As recv(), but gives up once the timeout has passed. The timeout is
+    // only as precise as the scheduler's clock.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = waitqueue::now() + timeout;
+
+        loop {
+            match self.try_recv() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            if waitqueue::now() >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+
+            if let Some(me) = self.wait_queue() {
+                waitqueue::block_until(deadline);
+                self.shared.lock().unwrap().receiving.remove(me);
+            }
+        }
+    }
+
+    // Joins the wait queue, unless a message or disconnection snuck in since
+    // we last looked.
+    fn wait_queue(&self) -> Option<usize> {
+        let mut state = self.shared.lock().unwrap();
+        if !state.queue.is_empty() || state.senders == 0 {
+            return None;
+        }
+
+        Some(state.receiving.enqueue())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.lock().unwrap().senders += 1;
+        Sender{shared: Arc::clone(&self.shared)}
+    }
+}
+
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> SyncSender<T> {
+        self.shared.lock().unwrap().senders += 1;
+        SyncSender{shared: Arc::clone(&self.shared)}
+    }
+}
+
+// The last sender going away wakes the receiver so it sees the disconnect.
+fn drop_sender<T>(shared: &Spinlock<Shared<T>>) {
+    let mut state = shared.lock().unwrap();
+    state.senders -= 1;
+    let receiver = if state.senders == 0 { state.receiving.dequeue() } else { None };
+    drop(state);
+
+    if let Some(id) = receiver {
+        waitqueue::wake(id);
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        drop_sender(&self.shared);
+    }
+}
+
+impl<T> Drop for SyncSender<T> {
+    fn drop(&mut self) {
+        drop_sender(&self.shared);
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.receiver_alive = false;
+        let senders = state.sending.dequeue_all();
+        drop(state);
+
+        for id in senders {
+            waitqueue::wake(id);
+        }
+    }
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "SendError(..)".fmt(f)
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TrySendError::Full(..) => "Full(..)".fmt(f),
+            TrySendError::Disconnected(..) => "Disconnected(..)".fmt(f),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use super::{LockResult, Spinlock};
+use super::mutex::MutexGuard;
+use super::waitqueue::{self, WaitQueue};
+
+// Lets threads wait for a condition protected by a Mutex. As with any
+// condition variable, wakes can be spurious, so wait in a loop that checks
+// the condition.
+pub struct Condvar {
+    waiters: Spinlock<WaitQueue>,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar{waiters: Spinlock::new(WaitQueue::new())}
+    }
+
+    // Unlocks the mutex and parks the calling thread until notified, then
+    // relocks the mutex before returning.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
+        let mutex = guard.mutex();
+
+        // Queue up before unlocking, so a notify that comes in as soon as
+        // the mutex is released still reaches us.
+        let me = self.waiters.lock().unwrap().enqueue();
+        drop(guard);
+
+        waitqueue::block();
+
+        // Still queued if the wake came from somewhere else.
+        self.waiters.lock().unwrap().remove(me);
+
+        mutex.lock()
+    }
+
+    pub fn wait_while<'a, T: ?Sized, F>(&self, mut guard: MutexGuard<'a, T>, mut condition: F) -> LockResult<MutexGuard<'a, T>>
+    where
+        F: FnMut(&mut T) -> bool
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+
+        Ok(guard)
+    }
+
+    pub fn notify_one(&self) {
+        let next = self.waiters.lock().unwrap().dequeue();
+        if let Some(id) = next {
+            waitqueue::wake(id);
+        }
+    }
+
+    pub fn notify_all(&self) {
+        let all = self.waiters.lock().unwrap().dequeue_all();
+        for id in all {
+            waitqueue::wake(id);
+        }
+    }
+}
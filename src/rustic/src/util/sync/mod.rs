@@ -26,6 +26,18 @@ use core::fmt;
 use crate::Kernel;
 use crate::arch::Architecture;
 
+pub mod channel;
+mod condvar;
+mod mutex;
+mod semaphore;
+mod waitqueue;
+
+// Blocking primitives, for threads to wait on each other. Unlike Spinlock,
+// these leave interrupts enabled and let other threads run while waiting.
+pub use self::condvar::Condvar;
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::semaphore::Semaphore;
+
 pub struct Spinlock<T: ?Sized> {
     atom: AtomicBool,
     interrupts: AtomicBool,
@@ -57,7 +69,7 @@ unsafe impl<T: ?Sized + Send> Send for Spinlock<T> {}
 unsafe impl<T: ?Sized + Send> Sync for Spinlock<T> {}
 
 impl<T> Spinlock<T> {
-    pub fn new(t: T) -> Spinlock<T> {
+    pub const fn new(t: T) -> Spinlock<T> {
         return Spinlock { atom: AtomicBool::new(false), interrupts: AtomicBool::new(false), data: UnsafeCell::new(t) };
     }
 }
@@ -99,13 +111,30 @@ impl<T: ?Sized> Spinlock<T> {
 
     pub fn try_lock(&self) -> TryLockResult<SpinlockGuard<'_, T>> {
         unsafe {
+            // As for lock(), as the guard restores the interrupt state.
+            let was = Kernel::get_interrupts_static();
+            Kernel::set_interrupts_static(false);
+
             match self.atom.compare_exchange(false, true, atomic::Ordering::Acquire, atomic::Ordering::Acquire) {
-                Ok(_) => Ok(SpinlockGuard::new(self)?),
-                Err(_) => Err(TryLockError::WouldBlock)
+                Ok(_) => {
+                    self.interrupts.store(was, atomic::Ordering::Release);
+                    Ok(SpinlockGuard::new(self)?)
+                },
+                Err(_) => {
+                    Kernel::set_interrupts_static(was);
+                    Err(TryLockError::WouldBlock)
+                }
             }
         }
     }
 
+    // Releases the lock without restoring the interrupt state saved by
+    // lock(), for code that hands the CPU over to another context with
+    // interrupts still disabled. The guard must be forgotten, not dropped.
+    pub unsafe fn force_unlock(&self) {
+        self.atom.store(false, atomic::Ordering::Release);
+    }
+
     pub fn get_mut(&mut self) -> LockResult<&mut T> {
         let data = self.data.get_mut();
         Ok(data)
@@ -0,0 +1,129 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use super::{LockResult, Spinlock, TryLockError, TryLockResult};
+use super::waitqueue::{self, WaitQueue};
+
+// A lock that parks contending threads rather than spinning, so it can be
+// held for as long as needed - including across blocking calls - without
+// stopping other threads or interrupts. Must not be used from interrupt
+// handlers or with the kernel locked.
+pub struct Mutex<T: ?Sized> {
+    state: Spinlock<MutexState>,
+    data: UnsafeCell<T>
+}
+
+struct MutexState {
+    locked: bool,
+    waiters: WaitQueue,
+}
+
+#[must_use = "if unused the Mutex will immediately unlock"]
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a Mutex<T>
+}
+
+impl<T: ?Sized> !Send for MutexGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(t: T) -> Mutex<T> {
+        Mutex{
+            state: Spinlock::new(MutexState{locked: false, waiters: WaitQueue::new()}),
+            data: UnsafeCell::new(t)
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        loop {
+            let mut state = self.state.lock().unwrap();
+            if !state.locked {
+                state.locked = true;
+                return Ok(MutexGuard{lock: self});
+            }
+
+            state.waiters.enqueue();
+            drop(state);
+
+            waitqueue::block();
+        }
+    }
+
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        let mut state = self.state.lock().unwrap();
+        if state.locked {
+            Err(TryLockError::WouldBlock)
+        } else {
+            state.locked = true;
+            Ok(MutexGuard{lock: self})
+        }
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        Ok(self.data.get_mut())
+    }
+
+    fn unlock(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.locked {
+            panic!("trying to unlock an already unlocked mutex");
+        }
+
+        state.locked = false;
+        let next = state.waiters.dequeue();
+        drop(state);
+
+        // The woken thread still has to compete for the lock.
+        if let Some(id) = next {
+            waitqueue::wake(id);
+        }
+    }
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    // For Condvar, which needs to get back to the mutex after unlocking it.
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.lock
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
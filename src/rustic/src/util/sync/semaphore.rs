@@ -0,0 +1,76 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use super::Spinlock;
+use super::waitqueue::{self, WaitQueue};
+
+// A counting semaphore. acquire() parks the calling thread until a unit is
+// available. Must not be used from interrupt handlers or with the kernel
+// locked, although release() is fine from anywhere a Mutex can be unlocked.
+pub struct Semaphore {
+    state: Spinlock<SemaphoreState>,
+}
+
+struct SemaphoreState {
+    count: usize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore{state: Spinlock::new(SemaphoreState{count: count, waiters: WaitQueue::new()})}
+    }
+
+    pub fn acquire(&self) {
+        loop {
+            let mut state = self.state.lock().unwrap();
+            if state.count > 0 {
+                state.count -= 1;
+                return;
+            }
+
+            state.waiters.enqueue();
+            drop(state);
+
+            waitqueue::block();
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.count > 0 {
+            state.count -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.count += 1;
+        let next = state.waiters.dequeue();
+        drop(state);
+
+        if let Some(id) = next {
+            waitqueue::wake(id);
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        self.state.lock().unwrap().count
+    }
+}
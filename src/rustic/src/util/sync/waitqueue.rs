@@ -0,0 +1,79 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use alloc::collections::VecDeque;
+
+use crate::Kernel;
+use crate::arch::Threads;
+use crate::util::time::Instant;
+
+// Threads waiting for something, in the order they started waiting. Always
+// kept inside the Spinlock protecting whatever it is they are waiting for.
+pub struct WaitQueue {
+    waiters: VecDeque<usize>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue{waiters: VecDeque::new()}
+    }
+
+    // Adds the calling thread. It should then drop the surrounding lock and
+    // call block().
+    pub fn enqueue(&mut self) -> usize {
+        let me = current_thread();
+        if !self.waiters.contains(&me) {
+            self.waiters.push_back(me);
+        }
+        me
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        self.waiters.retain(|&t| t != id);
+    }
+
+    // These hand back the threads to wake, which should be done with
+    // wake() once the surrounding lock is released.
+    pub fn dequeue(&mut self) -> Option<usize> {
+        self.waiters.pop_front()
+    }
+
+    pub fn dequeue_all(&mut self) -> VecDeque<usize> {
+        core::mem::replace(&mut self.waiters, VecDeque::new())
+    }
+}
+
+pub fn current_thread() -> usize {
+    Kernel::kernel().lock().unwrap().thread_id()
+}
+
+// A wake that lands between enqueue() and block() just makes block() return
+// straight away, so nothing is lost in that window.
+pub fn block() {
+    Kernel::thread_block(Kernel::kernel());
+}
+
+pub fn block_until(deadline: Instant) {
+    Kernel::thread_block_until(Kernel::kernel(), deadline);
+}
+
+pub fn now() -> Instant {
+    Kernel::kernel().lock().unwrap().now()
+}
+
+pub fn wake(id: usize) {
+    Kernel::kernel().lock().unwrap().thread_wake(id);
+}
@@ -0,0 +1,49 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use core::ops::Add;
+use core::time::Duration;
+
+// A point in time, as counted by the scheduler's clock. Only as precise as
+// the timer tick.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ms: u64,
+}
+
+impl Instant {
+    pub fn from_ms(ms: u64) -> Instant {
+        Instant{ms: ms}
+    }
+
+    // Milliseconds since the clock started.
+    pub fn as_ms(&self) -> u64 {
+        self.ms
+    }
+
+    // Zero if `earlier` is actually later than this instant.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_millis(self.ms.saturating_sub(earlier.ms))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, other: Duration) -> Instant {
+        Instant{ms: self.ms.saturating_add(other.as_millis() as u64)}
+    }
+}
@@ -0,0 +1,118 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// XMODEM-CRC and YMODEM transfers over the serial line, for getting data on
+// and off a board (see the framing crate for the protocols). Start the
+// transfer here first, then `sx`/`sb` (or `rx`/`rb`) on the other end.
+// Nothing else should write to the debug port while a transfer is running.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use framing::xmodem::{self, Line};
+
+use crate::Kernel;
+use crate::arch::Threads;
+use crate::mach::Serial;
+
+use super::link::DebugPort;
+
+pub use framing::xmodem::{Error, Sink};
+
+// How often to check for incoming bytes while waiting.
+static POLL_MS: usize = 10;
+
+pub struct File {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+// Collects files on the heap.
+struct Files {
+    files: Vec<File>,
+}
+
+impl Line for DebugPort {
+    fn read(&mut self, timeout_ms: u64) -> Option<u8> {
+        let locked_kernel = Kernel::kernel();
+        let deadline = locked_kernel.lock().unwrap().now().as_ms() + timeout_ms;
+
+        loop {
+            let kernel = locked_kernel.lock().unwrap();
+            if let Some(byte) = kernel.serial_try_read() {
+                return Some(byte);
+            }
+
+            if kernel.now().as_ms() >= deadline {
+                return None;
+            }
+            drop(kernel);
+
+            Kernel::sleep_ms(Arc::clone(&locked_kernel), POLL_MS);
+        }
+    }
+}
+
+impl Sink for Files {
+    fn file(&mut self, name: &str, size: Option<usize>) -> bool {
+        self.files.push(File{name: String::from(name), data: Vec::with_capacity(size.unwrap_or(0))});
+        true
+    }
+
+    fn write(&mut self, data: &[u8]) -> bool {
+        match self.files.last_mut() {
+            Some(file) => {
+                file.data.extend_from_slice(data);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+// XMODEM can't say how long the file is, so it comes back padded out to a
+// multiple of 128 bytes with 0x1A.
+pub fn receive_xmodem() -> Result<Vec<u8>, Error> {
+    let mut files = Files{files: Vec::new()};
+    xmodem::xmodem_receive(&mut DebugPort, &mut files)?;
+    Ok(files.files.pop().map_or(Vec::new(), |f| f.data))
+}
+
+pub fn receive_ymodem() -> Result<Vec<File>, Error> {
+    let mut files = Files{files: Vec::new()};
+    xmodem::ymodem_receive(&mut DebugPort, &mut files)?;
+    Ok(files.files)
+}
+
+// As above, but the data goes straight to the sink rather than the heap.
+// Returns how many bytes, or files, were received.
+pub fn receive_xmodem_into<S: Sink>(sink: &mut S) -> Result<usize, Error> {
+    xmodem::xmodem_receive(&mut DebugPort, sink)
+}
+
+pub fn receive_ymodem_into<S: Sink>(sink: &mut S) -> Result<usize, Error> {
+    xmodem::ymodem_receive(&mut DebugPort, sink)
+}
+
+pub fn send_xmodem(data: &[u8]) -> Result<(), Error> {
+    xmodem::xmodem_send(&mut DebugPort, data)
+}
+
+// Each file is a (name, data) pair.
+pub fn send_ymodem(files: &[(&str, &[u8])]) -> Result<(), Error> {
+    xmodem::ymodem_send(&mut DebugPort, files)
+}
@@ -0,0 +1,234 @@
+/*
+ * Copyright (c) 2014 Matthew Iselin
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Bitmap physical frame allocator. One bit per 4 KiB frame, covering the
+// entire 32-bit physical address space. A set bit means the frame is free;
+// everything starts out in use, so only memory that the machine layer has
+// explicitly handed over with add_region() is ever allocated.
+
+pub const FRAME_SIZE: usize = 4096;
+
+const MAX_FRAMES: usize = 1 << 20;
+const BITMAP_WORDS: usize = MAX_FRAMES / 32;
+
+pub struct FrameBitmap {
+    bitmap: [u32; BITMAP_WORDS],
+    free: usize,
+    top_frame: usize,
+
+    // Lowest word in the bitmap that might have a free frame in it.
+    hint: usize,
+}
+
+impl FrameBitmap {
+    // All zeroes, so a static FrameBitmap lands in .bss.
+    pub const fn new() -> FrameBitmap {
+        FrameBitmap{bitmap: [0; BITMAP_WORDS], free: 0, top_frame: 0, hint: BITMAP_WORDS}
+    }
+
+    // Marks every frame entirely contained in the given region as free.
+    pub fn add_region(&mut self, base: u64, length: u64) {
+        let first = frame_ceil(base);
+        let last = frame_floor(base.saturating_add(length));
+
+        for frame in first..last {
+            if !self.is_free(frame) {
+                self.set_free(frame, true);
+            }
+        }
+
+        if first < last && last > self.top_frame {
+            self.top_frame = last;
+        }
+    }
+
+    // Marks every frame that overlaps the given region as in use.
+    pub fn reserve_region(&mut self, base: u64, length: u64) {
+        let first = frame_floor(base);
+        let last = frame_ceil(base.saturating_add(length));
+
+        for frame in first..last {
+            if self.is_free(frame) {
+                self.set_free(frame, false);
+            }
+        }
+    }
+
+    pub fn alloc_frame(&mut self) -> Option<usize> {
+        for word in self.hint..BITMAP_WORDS {
+            let bits = self.bitmap[word];
+            if bits == 0 {
+                continue;
+            }
+
+            self.hint = word;
+
+            let frame = (word * 32) + bits.trailing_zeros() as usize;
+            self.set_free(frame, false);
+            return Some(frame * FRAME_SIZE);
+        }
+
+        self.hint = BITMAP_WORDS;
+        None
+    }
+
+    pub fn free_frame(&mut self, addr: usize) {
+        let frame = addr / FRAME_SIZE;
+        if frame >= MAX_FRAMES || self.is_free(frame) {
+            panic!("freeing frame {:#x} which is not allocated", addr);
+        }
+
+        self.set_free(frame, true);
+    }
+
+    // First-fit search for a run of physically contiguous frames.
+    pub fn alloc_contiguous(&mut self, count: usize) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+
+        let mut start = self.hint * 32;
+        let mut run = 0;
+        let mut frame = start;
+        while frame < MAX_FRAMES {
+            if (frame & 31) == 0 && run == 0 && self.bitmap[frame / 32] == 0 {
+                // Whole word is in use, skip it.
+                frame += 32;
+                start = frame;
+                continue;
+            }
+
+            if self.is_free(frame) {
+                run += 1;
+                if run == count {
+                    for f in start..(start + count) {
+                        self.set_free(f, false);
+                    }
+
+                    return Some(start * FRAME_SIZE);
+                }
+            } else {
+                run = 0;
+                start = frame + 1;
+            }
+
+            frame += 1;
+        }
+
+        None
+    }
+
+    pub fn free_count(&self) -> usize {
+        self.free
+    }
+
+    // Address just past the highest frame of usable memory. Saturates if
+    // usable memory runs right up to the end of the address space.
+    pub fn memory_top(&self) -> usize {
+        self.top_frame.saturating_mul(FRAME_SIZE)
+    }
+
+    fn is_free(&self, frame: usize) -> bool {
+        (self.bitmap[frame / 32] & (1 << (frame % 32))) != 0
+    }
+
+    fn set_free(&mut self, frame: usize, free: bool) {
+        let word = frame / 32;
+        if free {
+            self.bitmap[word] |= 1 << (frame % 32);
+            self.free += 1;
+
+            if word < self.hint {
+                self.hint = word;
+            }
+        } else {
+            self.bitmap[word] &= !(1 << (frame % 32));
+            self.free -= 1;
+        }
+    }
+}
+
+impl Default for FrameBitmap {
+    fn default() -> FrameBitmap {
+        FrameBitmap::new()
+    }
+}
+
+fn frame_floor(addr: u64) -> usize {
+    core::cmp::min(addr / FRAME_SIZE as u64, MAX_FRAMES as u64) as usize
+}
+
+fn frame_ceil(addr: u64) -> usize {
+    frame_floor(addr.saturating_add(FRAME_SIZE as u64 - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_and_reservations() {
+        let mut frames = FrameBitmap::new();
+        assert_eq!(None, frames.alloc_frame());
+
+        // Partial frames at either end of a usable region are not usable.
+        frames.add_region(0x100800, 0x3000);
+        assert_eq!(2, frames.free_count());
+        assert_eq!(0x103000, frames.memory_top());
+
+        // Partial frames at either end of a reservation are reserved.
+        frames.reserve_region(0x101fff, 2);
+        assert_eq!(0, frames.free_count());
+        assert_eq!(None, frames.alloc_frame());
+    }
+
+    #[test]
+    fn alloc_and_free() {
+        let mut frames = FrameBitmap::new();
+        frames.add_region(0x200000, 0x4000);
+
+        assert_eq!(Some(0x200000), frames.alloc_frame());
+        assert_eq!(Some(0x201000), frames.alloc_frame());
+        frames.free_frame(0x200000);
+        assert_eq!(Some(0x200000), frames.alloc_frame());
+        assert_eq!(Some(0x202000), frames.alloc_frame());
+        assert_eq!(Some(0x203000), frames.alloc_frame());
+        assert_eq!(None, frames.alloc_frame());
+    }
+
+    #[test]
+    fn contiguous() {
+        let mut frames = FrameBitmap::new();
+        frames.add_region(0, 0x100000);
+        frames.reserve_region(0x3000, 0x1000);
+
+        assert_eq!(Some(0x0), frames.alloc_contiguous(3));
+        assert_eq!(Some(0x4000), frames.alloc_contiguous(64));
+        assert_eq!(Some(0x44000), frames.alloc_contiguous(1));
+        assert_eq!(None, frames.alloc_contiguous(256));
+        assert_eq!(256 - 3 - 1 - 64 - 1, frames.free_count());
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let mut frames = FrameBitmap::new();
+        frames.add_region(0, 0x2000);
+        let frame = frames.alloc_frame().unwrap();
+        frames.free_frame(frame);
+        frames.free_frame(frame);
+    }
+}
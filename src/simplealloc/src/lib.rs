@@ -1,33 +1,288 @@
 
 #![no_std]
 
-static mut HEAP_BASE: usize = 0x200000;
+// The unsafe functions here follow the same contract as GlobalAlloc.
+#![allow(clippy::missing_safety_doc)]
 
-pub unsafe fn direct_alloc(sz: usize, align: usize) -> *mut u8 {
-    if (HEAP_BASE & (align -1)) != 0 {
-        HEAP_BASE += align - (HEAP_BASE % align);
+pub mod frames;
+
+use core::cmp;
+use core::mem::size_of;
+use core::ptr;
+
+// Free blocks are kept in an address-ordered singly linked list, with the
+// list node living in the free memory itself. Allocated blocks carry no
+// header - callers hand the size and alignment back when freeing, just as
+// GlobalAlloc does. Every block is a multiple of MIN_BLOCK in size and
+// starts on a MIN_BLOCK boundary, so any leftover piece from splitting a
+// block is always big enough to hold a list node.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const MIN_BLOCK: usize = size_of::<FreeBlock>();
+
+pub struct Heap {
+    head: *mut FreeBlock,
+    free: usize,
+}
+
+// The heap only hands out memory it has been given, so it is fine to move
+// it between threads as long as access is serialised by the owner.
+unsafe impl Send for Heap {}
+
+impl Heap {
+    pub const fn new() -> Heap {
+        Heap{head: ptr::null_mut(), free: 0}
+    }
+
+    // Hands a region of memory over to the heap. Any partial blocks at the
+    // start or end of the region are ignored.
+    pub unsafe fn add_region(&mut self, base: usize, size: usize) {
+        let start = align_up(base, MIN_BLOCK);
+        let end = (base + size) & !(MIN_BLOCK - 1);
+        if end > start {
+            self.insert(start, end - start);
+        }
+    }
+
+    // First-fit allocation. Returns null if nothing big enough is free.
+    pub unsafe fn allocate(&mut self, size: usize, align: usize) -> *mut u8 {
+        let size = block_size(size);
+        let align = cmp::max(align, MIN_BLOCK);
+
+        let mut link: *mut *mut FreeBlock = &mut self.head;
+        while !(*link).is_null() {
+            let block = *link;
+            let start = block as usize;
+            let end = start + (*block).size;
+
+            let alloc_start = align_up(start, align);
+            let alloc_end = match alloc_start.checked_add(size) {
+                Some(alloc_end) if alloc_end <= end => alloc_end,
+                _ => {
+                    link = &mut (*block).next;
+                    continue;
+                }
+            };
+
+            // Unlink the block, and put back whatever is left over on either
+            // side of the allocation (which keeps the list in order).
+            let next = (*block).next;
+            if alloc_start > start {
+                *link = write_block(start, alloc_start - start);
+                link = &mut (**link).next;
+            }
+
+            if alloc_end < end {
+                *link = write_block(alloc_end, end - alloc_end);
+                link = &mut (**link).next;
+            }
+
+            *link = next;
+
+            self.free -= size;
+            return alloc_start as *mut u8;
+        }
+
+        ptr::null_mut()
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, size: usize, _align: usize) {
+        self.insert(ptr as usize, block_size(size));
+    }
+
+    // Resizes an allocation without moving it, if possible. Shrinking always
+    // works; growing only works if the memory right after the allocation is
+    // free. Returns false if the caller needs to allocate and copy instead.
+    pub unsafe fn reallocate_in_place(&mut self, ptr: *mut u8, old_size: usize, new_size: usize, _align: usize) -> bool {
+        let old_size = block_size(old_size);
+        let new_size = block_size(new_size);
+        let start = ptr as usize;
+
+        if new_size <= old_size {
+            if new_size < old_size {
+                self.insert(start + new_size, old_size - new_size);
+            }
+
+            return true;
+        }
+
+        let wanted = new_size - old_size;
+        let mut link: *mut *mut FreeBlock = &mut self.head;
+        while !(*link).is_null() && (*link as usize) < start + old_size {
+            link = &mut (**link).next;
+        }
+
+        let block = *link;
+        if block.is_null() || (block as usize) != start + old_size || (*block).size < wanted {
+            return false;
+        }
+
+        let next = (*block).next;
+        let remaining = (*block).size - wanted;
+        if remaining > 0 {
+            *link = write_block(start + new_size, remaining);
+            (**link).next = next;
+        } else {
+            *link = next;
+        }
+
+        self.free -= wanted;
+        true
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        self.free
+    }
+
+    // Puts a block back on the free list, merging it with its neighbours.
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        if (!prev.is_null() && (prev as usize) + (*prev).size > addr) ||
+           (!next.is_null() && addr + size > (next as usize)) {
+            panic!("heap corruption: freeing {:#x} (+{:#x}) which overlaps a free block", addr, size);
+        }
+
+        self.free += size;
+
+        let block = write_block(addr, size);
+        (*block).next = next;
+
+        if !next.is_null() && addr + size == (next as usize) {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if (prev as usize) + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
     }
+}
 
-    let result = HEAP_BASE;
-    HEAP_BASE += sz;
+unsafe fn write_block(addr: usize, size: usize) -> *mut FreeBlock {
+    let block = addr as *mut FreeBlock;
+    ptr::write(block, FreeBlock{size, next: ptr::null_mut()});
+    block
+}
 
-    result as *mut u8
+fn block_size(size: usize) -> usize {
+    align_up(cmp::max(size, 1), MIN_BLOCK)
 }
 
-pub unsafe fn direct_dealloc(_ptr: *mut u8) {
-    // does nothing as we're just incrementing a heap base
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[repr(C, align(4096))]
+    struct Arena([u8; 0x1000]);
+
+    fn arena_heap(arena: &mut Arena) -> (Heap, usize) {
+        let base = arena.0.as_mut_ptr() as usize;
+        let mut heap = Heap::new();
+        unsafe { heap.add_region(base, arena.0.len()) };
+        (heap, base)
+    }
+
     #[test]
     fn aligned_allocs() {
-        assert_eq!(0x200000 as *mut u8, unsafe { direct_alloc(0x100, 0x10) });
-        assert_eq!(0x200100 as *mut u8, unsafe { direct_alloc(0x123, 0x8) });
-        assert_eq!(0x200230 as *mut u8, unsafe { direct_alloc(0x100, 0x10) });
-        assert_eq!(0x200330 as *mut u8, unsafe { direct_alloc(0x3, 0x10) });
-        assert_eq!(0x200340 as *mut u8, unsafe { direct_alloc(0x3, 0x10) });
+        let mut arena = Arena([0; 0x1000]);
+        let (mut heap, base) = arena_heap(&mut arena);
+
+        let allocs = [(0x100, 0x10), (0x123, 0x8), (0x100, 0x10), (0x3, 0x10), (0x3, 0x10), (0x10, 0x100)];
+        let mut last = 0;
+        for &(size, align) in allocs.iter() {
+            let ptr = unsafe { heap.allocate(size, align) } as usize;
+            assert!(ptr >= base && ptr + size <= base + 0x1000);
+            assert_eq!(0, ptr % align);
+            assert!(ptr > last);
+            last = ptr;
+        }
+
+        // Region exhausted.
+        assert!(unsafe { heap.allocate(0x1000, 0x10) }.is_null());
+    }
+
+    #[test]
+    fn free_and_coalesce() {
+        let mut arena = Arena([0; 0x1000]);
+        let (mut heap, base) = arena_heap(&mut arena);
+        let total = heap.free_bytes();
+
+        unsafe {
+            let a = heap.allocate(0x100, 0x10);
+            let b = heap.allocate(0x100, 0x10);
+            let c = heap.allocate(0x100, 0x10);
+            assert_eq!(total - 0x300, heap.free_bytes());
+
+            // Freeing the first two should leave one block big enough for
+            // both, right where they were.
+            heap.deallocate(b, 0x100, 0x10);
+            heap.deallocate(a, 0x100, 0x10);
+            assert_eq!(a, heap.allocate(0x200, 0x10));
+            heap.deallocate(a, 0x200, 0x10);
+
+            // And with everything freed the whole region is usable again.
+            heap.deallocate(c, 0x100, 0x10);
+            assert_eq!(total, heap.free_bytes());
+            assert_eq!(base as *mut u8, heap.allocate(0x1000, 0x10));
+        }
+    }
+
+    #[test]
+    fn realloc_in_place() {
+        let mut arena = Arena([0; 0x1000]);
+        let (mut heap, _) = arena_heap(&mut arena);
+        let total = heap.free_bytes();
+
+        unsafe {
+            let a = heap.allocate(0x100, 0x10);
+            assert!(heap.reallocate_in_place(a, 0x100, 0x800, 0x10));
+            assert!(heap.reallocate_in_place(a, 0x800, 0x40, 0x10));
+            assert_eq!(total - 0x40, heap.free_bytes());
+
+            // Can't grow into something that's in use.
+            let b = heap.allocate(0x40, 0x10);
+            assert!(!heap.reallocate_in_place(a, 0x40, 0x80, 0x10));
+
+            heap.deallocate(b, 0x40, 0x10);
+            heap.deallocate(a, 0x40, 0x10);
+            assert_eq!(total, heap.free_bytes());
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let mut arena = Arena([0; 0x1000]);
+        let (mut heap, _) = arena_heap(&mut arena);
+
+        unsafe {
+            let a = heap.allocate(0x100, 0x10);
+            heap.deallocate(a, 0x100, 0x10);
+            heap.deallocate(a, 0x100, 0x10);
+        }
     }
 }